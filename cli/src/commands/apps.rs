@@ -1,6 +1,5 @@
 use anyhow::Result;
-use common::{ManageRequest, ManageResponse, recv_json, send_json};
-use tokio::net::TcpStream;
+use common::{ManageRequest, Request, Response};
 use tracing::info;
 
 pub async fn start(host: String, port: u16, app: String) -> Result<()> {
@@ -19,32 +18,75 @@ pub async fn rollback(host: String, port: u16, app: String) -> Result<()> {
     manage(host, port, app, "rollback".to_string()).await
 }
 
+// [storage] volume: where it is, its size and backups
+pub async fn volume(host: String, port: u16, app: String) -> Result<()> {
+    let message = call(host, port, app, "volume".to_string(), None).await?;
+    println!("{}", message);
+    Ok(())
+}
+
+pub async fn backup(host: String, port: u16, app: String) -> Result<()> {
+    let message = call(host, port, app, "backup".to_string(), None).await?;
+    println!("{}", message);
+    Ok(())
+}
+
+// the newest backup without `backup`
+pub async fn restore(host: String, port: u16, app: String, backup: Option<String>) -> Result<()> {
+    let message = call(host, port, app, "restore".to_string(), backup).await?;
+    println!("{}", message);
+    Ok(())
+}
+
 async fn manage(host: String, port: u16, app: String, action: String) -> Result<()> {
-    let addr = format!("{}:{}", host, port);
+    match call(host, port, app, action, None).await {
+        Ok(message) => info!("SUCCESS: {}", message),
+        Err(e) => tracing::error!("ERROR: {}", e),
+    }
+    Ok(())
+}
 
+async fn call(
+    host: String,
+    port: u16,
+    app: String,
+    action: String,
+    arg: Option<String>,
+) -> Result<String> {
     // TODO: In this moment it's have only on localhost.
-    let tcp = TcpStream::connect(&addr).await?;
-
-    let mut socket = crate::tls::connect(tcp, &host).await?;
+    let session = crate::session::open(&host, port).await?;
 
     let app_normalize = app.replace("/", "_");
 
-    let req = ManageRequest {
-        msg_type: "manage".into(),
+    let req = Request::Manage(ManageRequest {
         app: app_normalize,
         action: action,
-    };
+        arg,
+        daemon_token: token(&host, port),
+    });
 
-    send_json(&mut socket, &req).await?;
-    let resp: ManageResponse = recv_json(&mut socket).await?;
+    let resp = match session.call(req).await? {
+        Response::Manage(r) => r,
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    };
 
-    if resp.success {
-        info!("SUCCESS: {}", resp.message);
-    } else {
-        tracing::error!("ERROR: {}", resp.message);
+    if !resp.success {
+        anyhow::bail!("{}", resp.message);
     }
+    Ok(resp.message)
+}
 
-    Ok(())
+// the token of the paired device at host:port, the local socket needs none
+fn token(host: &str, port: u16) -> Option<String> {
+    if crate::session::is_local() {
+        return None;
+    }
+    let config = common::load_config().ok()?;
+    config
+        .devices
+        .into_iter()
+        .find(|d| d.host == host && d.port == port)
+        .and_then(|d| d.token)
 }
 
 // pub fn rollback(app: &str) -> Result<()> {
@@ -74,7 +116,3 @@ async fn manage(host: String, port: u16, app: String, action: String) -> Result<
 //     info!("Rolled back: {}", app);
 //     Ok(())
 // }
-
-pub async fn rollback(host: String, port: u16, app: String) -> Result<()> {
-    manage(host, port, app, "rollback".to_string()).await
-}
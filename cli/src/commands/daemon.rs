@@ -0,0 +1,82 @@
+use anyhow::Result;
+use common::{Request, Response, TransferTarget, UpgradeRequest};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::info;
+
+// how long the daemon gets to come back with the new binary
+const RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// `flare daemon upgrade --binary ./flared`: push a new flared and wait for it to take over
+pub async fn upgrade(
+    host: String,
+    port: u16,
+    device: Option<String>,
+    binary: PathBuf,
+    signature: Option<PathBuf>,
+) -> Result<()> {
+    let (host, port, token) = match device {
+        Some(dev) if !crate::session::is_local() => {
+            let device = common::get_device(&dev)?;
+            (device.host, device.port, device.token)
+        }
+        _ => (host, port, None),
+    };
+
+    let sha256 = common::sha256_file(&binary)?;
+    let signature = match signature {
+        Some(path) => {
+            Some(hex::encode(std::fs::read(&path).map_err(|e| {
+                anyhow::anyhow!("Can't read signature {:?}: {}", path, e)
+            })?))
+        }
+        None => None,
+    };
+
+    let session = crate::session::open(&host, port).await?;
+    let before = session.daemon.daemon.clone();
+
+    let transfer = format!("flared-{}", &sha256[..16]);
+    let size = session
+        .upload(
+            &transfer,
+            TransferTarget::DaemonBinary,
+            &binary,
+            token.clone(),
+        )
+        .await?;
+    info!("Uploaded {} bytes", size);
+
+    let req = Request::Upgrade(UpgradeRequest {
+        sha256,
+        signature,
+        daemon_token: token,
+    });
+    let resp = match session.call(req).await? {
+        Response::Upgrade(r) => r,
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    };
+    println!("{}", resp.message);
+    drop(session);
+
+    // the old daemon drains first, give it a moment before asking
+    let deadline = tokio::time::Instant::now() + RESTART_TIMEOUT;
+    let mut running = before;
+    while tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        if let Ok(session) = crate::session::open(&host, port).await {
+            running = session.daemon.daemon.clone();
+            if running == resp.version {
+                println!("Daemon is now running {}", running);
+                return Ok(());
+            }
+        }
+    }
+
+    anyhow::bail!(
+        "Daemon still reports {} after {:?}, the new binary may have been rolled back",
+        running,
+        RESTART_TIMEOUT
+    )
+}
@@ -1,6 +1,5 @@
 use anyhow::Result;
-use common::{DeployRequest, DeployResponse, recv_json, send_json};
-use tokio::net::TcpStream;
+use common::{DeployRequest, Request, Response};
 use tracing::{error, info};
 
 pub async fn run(
@@ -31,25 +30,25 @@ pub async fn run(
         auth.forge.unwrap_or(forge)
     };
 
-    let tcp = TcpStream::connect(format!("{}:{}", host, port)).await?;
-    let mut socket = crate::tls::connect(tcp, &host).await?;
+    let session = crate::session::open(&host, port).await?;
 
     info!("Connected to {}:{}", host, port);
 
     tracing::info!("Sending auth_user: {:?}", final_user);
     tracing::info!("Sending auth_password: {:?}", final_token);
 
-    let req = DeployRequest {
-        msg_type: "deploy".into(),
+    let req = Request::Deploy(DeployRequest {
         repo,
         forge: final_forge,
         auth_user: final_user,
         auth_password: final_token,
         daemon_token: None,
-    };
+    });
 
-    send_json(&mut socket, &req).await?;
-    let resp: DeployResponse = recv_json(&mut socket).await?;
+    let resp = match session.call(req).await? {
+        Response::Deploy(r) => r,
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    };
 
     if resp.success {
         info!("SUCCESS: {}", resp.message);
@@ -72,20 +71,20 @@ pub async fn run_to_device(
     info!("{:?}", device);
     let auth = crate::commands::auth::load().unwrap_or_default();
 
-    let tcp = TcpStream::connect(format!("{}:{}", device.host, device.port)).await?;
-    let mut socket = crate::tls::connect(tcp, &device.host).await?;
+    let session = crate::session::open(&device.host, device.port).await?;
 
-    let req = DeployRequest {
-        msg_type: "deploy".into(),
+    let req = Request::Deploy(DeployRequest {
         repo,
         forge: if github { "github".into() } else { forge },
         auth_user: user.or(auth.user),
         auth_password: token.or(auth.password),
         daemon_token: device.token.clone(),
-    };
+    });
 
-    send_json(&mut socket, &req).await?;
-    let resp: DeployResponse = recv_json(&mut socket).await?;
+    let resp = match session.call(req).await? {
+        Response::Deploy(r) => r,
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    };
 
     if resp.success {
         info!("SUCCESS: {}", resp.message);
@@ -1,5 +1,5 @@
 use anyhow::Result;
-use common::load_config;
+use common::{DeviceStatus, Request, Response, StatusRequest, StatusResponse, load_config};
 
 pub fn list() -> Result<()> {
     let config = load_config()?;
@@ -18,6 +18,98 @@ pub fn list() -> Result<()> {
     Ok(())
 }
 
+// `flare devices --status`: one block per device, unreachable ones say why
+pub async fn status() -> Result<()> {
+    let config = load_config()?;
+    if config.devices.is_empty() {
+        println!("No devices configured");
+        return Ok(());
+    }
+
+    for d in &config.devices {
+        let name = d.name.as_deref().unwrap_or("unnamed");
+        print!("[{}] {:16} {}:{}  ", d.id, name, d.host, d.port);
+        match fetch_status(&d.host, d.port, d.token.clone()).await {
+            Ok(status) => print_status(&status),
+            Err(e) => println!("unreachable: {}", e),
+        }
+    }
+    Ok(())
+}
+
+async fn fetch_status(host: &str, port: u16, token: Option<String>) -> Result<StatusResponse> {
+    let session = crate::session::open(host, port).await?;
+    match session
+        .call(Request::Status(StatusRequest {
+            daemon_token: token,
+        }))
+        .await?
+    {
+        Response::Status(s) => Ok(s),
+        Response::Error(e) => anyhow::bail!("{}", e.message),
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    }
+}
+
+fn print_status(status: &StatusResponse) {
+    println!("{}", status.daemon);
+    if let Some(dev) = &status.device {
+        print_device(dev);
+    }
+    for app in &status.apps {
+        let deployed = app
+            .deployed_at
+            .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+            .map(|t| format!("deployed {}", t.format("%Y-%m-%d %H:%M")))
+            .unwrap_or_default();
+        let health = app.health.as_deref().unwrap_or("");
+        println!(
+            "    {:20} {:10} {:10} {:10} {}",
+            app.name, app.version, app.status, health, deployed
+        );
+    }
+}
+
+fn print_device(dev: &DeviceStatus) {
+    let temp = dev
+        .temperature
+        .map(|t| format!("  {:.1}°C", t))
+        .unwrap_or_default();
+    println!(
+        "    {}  up {}  load {:.2} {:.2} {:.2} ({} cpus)  mem {} free of {}  disk {} free of {}{}",
+        dev.hostname,
+        duration(dev.uptime),
+        dev.load[0],
+        dev.load[1],
+        dev.load[2],
+        dev.cpus,
+        size(dev.memory_available),
+        size(dev.memory_total),
+        size(dev.disk_free),
+        size(dev.disk_total),
+        temp
+    );
+}
+
+fn duration(secs: u64) -> String {
+    match secs {
+        s if s >= 86400 => format!("{}d{}h", s / 86400, s % 86400 / 3600),
+        s if s >= 3600 => format!("{}h{}m", s / 3600, s % 3600 / 60),
+        s => format!("{}m", s / 60),
+    }
+}
+
+fn size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
 pub fn remove(id: &str) -> Result<()> {
     let mut config = common::load_config()?;
 
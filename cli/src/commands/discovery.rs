@@ -2,7 +2,6 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::io::{self, Write};
 use std::time::Duration;
-use tokio::net::TcpStream;
 use tokio::net::UdpSocket;
 use tracing::info;
 
@@ -58,12 +57,17 @@ pub async fn sync(range: String) -> Result<()> {
         info!("Generate token: {:?}", token_hash);
 
         // send hash to daemon
-        let registered = register_token(&device.host, device.port, &token_hash).await?;
+        let resp = register_token(&device.host, device.port, &token_hash).await?;
 
-        if !registered {
+        if !resp.success {
             println!("Failed to register token for {}", device.host);
             continue;
         }
+        if resp.pending {
+            println!("Token waits for approval, on {} run:", device.host);
+            println!("  flare --local pairing list");
+            println!("  flare --local pairing approve <id>");
+        }
 
         // get name
         print!("Name (optional): ");
@@ -108,10 +112,17 @@ pub async fn scan_network() -> Result<Vec<DiscoveredDevice>> {
 
     let _ = tokio::time::timeout(Duration::from_secs(2), async {
         loop {
-            if let Ok((_, addr)) = socket.recv_from(&mut buf).await {
+            if let Ok((len, addr)) = socket.recv_from(&mut buf).await {
+                // "FLARE_HERE <port>", older daemons send no port
+                let msg = String::from_utf8_lossy(&buf[..len]);
+                let port = msg
+                    .strip_prefix("FLARE_HERE")
+                    .and_then(|p| p.trim().parse().ok())
+                    .unwrap_or(7530);
+
                 let device = DiscoveredDevice {
                     host: addr.ip().to_string(),
-                    port: 7530,
+                    port,
                 };
 
                 if !found
@@ -155,19 +166,23 @@ fn parse_range(range: &str) -> Result<Vec<u32>> {
     Ok(result)
 }
 
-async fn register_token(host: &str, port: u16, token_hash: &str) -> Result<bool> {
-    use common::{RegisterTokenRequest, RegisterTokenResponse};
+async fn register_token(
+    host: &str,
+    port: u16,
+    token_hash: &str,
+) -> Result<common::RegisterTokenResponse> {
+    use common::{RegisterTokenRequest, Request, Response};
 
-    let tcp = TcpStream::connect(format!("{}:{}", host, port)).await?;
-    let mut socket = crate::tls::connect(tcp, host).await?;
+    let session = crate::session::open(host, port).await?;
 
-    let req = RegisterTokenRequest {
-        msg_type: "register_token".into(),
+    let req = Request::RegisterToken(RegisterTokenRequest {
         token_hash: token_hash.to_string(),
-    };
+    });
 
-    common::send_json(&mut socket, &req).await?;
-    let resp: RegisterTokenResponse = common::recv_json(&mut socket).await?;
+    let resp = match session.call(req).await? {
+        Response::RegisterToken(r) => r,
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    };
 
-    Ok(resp.success)
+    Ok(resp)
 }
@@ -0,0 +1,48 @@
+use anyhow::Result;
+use common::{Request, Response, SubscribeRequest};
+
+// Print daemon events (deploys, start/stop, ...) until Ctrl+C
+pub async fn follow(
+    host: String,
+    port: u16,
+    device: Option<String>,
+    apps: Vec<String>,
+) -> Result<()> {
+    let session = match device {
+        Some(dev) => {
+            let device = common::get_device(&dev)?;
+            crate::session::open(&device.host, device.port).await?
+        }
+        None => crate::session::open(&host, port).await?,
+    };
+
+    let apps = apps.iter().map(|a| a.replace("/", "_")).collect();
+    let mut stream = session
+        .stream(Request::Subscribe(SubscribeRequest { apps }))
+        .await?;
+
+    loop {
+        let resp = tokio::select! {
+            r = stream.next() => r,
+            _ = tokio::signal::ctrl_c() => {
+                session.cancel(&stream).await?;
+                return Ok(());
+            }
+        };
+
+        match resp {
+            Some(Ok(Response::Event(e))) => {
+                let time = chrono::DateTime::from_timestamp(e.timestamp, 0)
+                    .map(|t| t.format("%H:%M:%S").to_string())
+                    .unwrap_or_default();
+                println!("{} {:16} {:16} {}", time, e.app, e.kind, e.message);
+            }
+            Some(Ok(other)) => anyhow::bail!("Unexpected response: {:?}", other),
+            Some(Err(e)) => return Err(e),
+            None => {
+                println!("Daemon closed the stream");
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use anyhow::Result;
+use common::{ErrorResponse, TransferTarget};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// reconnect and resume this many times before giving up
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+// `flare cp ./file app:path` uploads, `flare cp app:path ./file` downloads
+pub async fn copy(
+    host: String,
+    port: u16,
+    device: Option<String>,
+    src: String,
+    dst: String,
+) -> Result<()> {
+    let (host, port, token) = match device {
+        Some(dev) => {
+            let device = common::get_device(&dev)?;
+            (device.host, device.port, device.token)
+        }
+        None => (host, port, None),
+    };
+
+    let upload = match (parse_remote(&src), parse_remote(&dst)) {
+        (None, Some(target)) => Some(target),
+        (Some(target), None) => {
+            return download(&host, port, token, target, Path::new(&dst)).await;
+        }
+        _ => None,
+    };
+
+    let target = upload.ok_or_else(|| anyhow::anyhow!("Exactly one side must be app:path"))?;
+    let path = Path::new(&src);
+    let transfer = transfer_id(path, &dst)?;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let result = async {
+            let session = crate::session::open(&host, port).await?;
+            session
+                .upload(&transfer, target.clone(), path, token.clone())
+                .await
+        }
+        .await;
+
+        match result {
+            Ok(size) => {
+                info!("SUCCESS: Uploaded {} bytes to {}", size, dst);
+                return Ok(());
+            }
+            Err(e) if attempt < MAX_ATTEMPTS && !is_refusal(&e) => {
+                warn!("Upload failed ({}), resuming in {:?}", e, RETRY_DELAY);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    unreachable!()
+}
+
+async fn download(
+    host: &str,
+    port: u16,
+    token: Option<String>,
+    target: TransferTarget,
+    dest: &Path,
+) -> Result<()> {
+    for attempt in 1..=MAX_ATTEMPTS {
+        let result = async {
+            let session = crate::session::open(host, port).await?;
+            session.download(target.clone(), dest, token.clone()).await
+        }
+        .await;
+
+        match result {
+            Ok(size) => {
+                info!("SUCCESS: Downloaded {} bytes to {:?}", size, dest);
+                return Ok(());
+            }
+            Err(e) if attempt < MAX_ATTEMPTS && !is_refusal(&e) => {
+                warn!("Download failed ({}), resuming in {:?}", e, RETRY_DELAY);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    unreachable!()
+}
+
+// daemon said no, retrying won't help
+fn is_refusal(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ErrorResponse>().is_some()
+}
+
+fn parse_remote(spec: &str) -> Option<TransferTarget> {
+    let (app, path) = spec.split_once(':')?;
+    Some(TransferTarget::AppFile {
+        app: app.replace("/", "_"),
+        path: path.to_string(),
+    })
+}
+
+// same file to the same place -> same id, so the daemon can resume it
+fn transfer_id(path: &Path, dst: &str) -> Result<String> {
+    let meta = std::fs::metadata(path)?;
+    let mut hasher = DefaultHasher::new();
+    std::fs::canonicalize(path)?.hash(&mut hasher);
+    meta.len().hash(&mut hasher);
+    meta.modified()?.hash(&mut hasher);
+    dst.hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
@@ -1,5 +1,9 @@
 pub mod apps;
 pub mod auth;
+pub mod daemon;
 pub mod deploy;
 pub mod devices;
 pub mod discovery;
+pub mod events;
+pub mod files;
+pub mod status;
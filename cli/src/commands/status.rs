@@ -0,0 +1,70 @@
+use anyhow::Result;
+use common::{PairingRequest, Request, Response, StatusRequest};
+
+pub async fn show(host: String, port: u16, device: Option<String>) -> Result<()> {
+    let (host, port, token) = match device {
+        Some(dev) if !crate::session::is_local() => {
+            let device = common::get_device(&dev)?;
+            (device.host, device.port, device.token)
+        }
+        _ => (host, port, None),
+    };
+
+    let session = crate::session::open(&host, port).await?;
+    let req = Request::Status(StatusRequest {
+        daemon_token: token,
+    });
+
+    let status = match session.call(req).await? {
+        Response::Status(s) => s,
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    };
+
+    println!("{}", status.daemon);
+    if status.apps.is_empty() {
+        println!("No apps deployed");
+    }
+    for app in &status.apps {
+        let pid = app.pid.map(|p| p.to_string()).unwrap_or_else(|| "-".into());
+        let oom = if app.oom_kills > 0 {
+            format!("  OOM-killed {}x", app.oom_kills)
+        } else {
+            String::new()
+        };
+        let health = match app.health.as_deref() {
+            Some(h) if h != "healthy" => format!("  {}", h),
+            _ => String::new(),
+        };
+        println!(
+            "{:20} {:10} {:10} pid {}{}{}",
+            app.name, app.version, app.status, pid, oom, health
+        );
+    }
+
+    Ok(())
+}
+
+// approve/reject tokens registered over the network, needs --local
+pub async fn pairing(action: String, token: Option<u32>) -> Result<()> {
+    if !crate::session::is_local() {
+        anyhow::bail!("Pairing works on the device only: flare --local pairing ...");
+    }
+
+    let session = crate::session::open("", 0).await?;
+    let req = Request::Pairing(PairingRequest { action, token });
+
+    let resp = match session.call(req).await? {
+        Response::Pairing(r) => r,
+        other => anyhow::bail!("Unexpected response: {:?}", other),
+    };
+
+    println!("{}", resp.message);
+    for p in &resp.pending {
+        let when = chrono::DateTime::from_timestamp(p.requested, 0)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        println!("[{}] {:24} {}", p.id, p.peer, when);
+    }
+
+    Ok(())
+}
@@ -1,8 +1,10 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 use tracing::error;
 
 mod commands;
+mod session;
 mod tls;
 
 #[derive(Parser)]
@@ -16,6 +18,10 @@ struct Cli {
 
     #[arg(long, default_value_t = 7530, global = true)]
     port: u16,
+
+    // talk to flared on this machine over its admin socket
+    #[arg(long, global = true)]
+    local: bool,
 }
 
 #[derive(Subcommand)]
@@ -49,6 +55,31 @@ enum Cmd {
     Rollback {
         app: String,
     },
+    // persistent [storage] of an app
+    Volume {
+        #[command(subcommand)]
+        action: VolumeAction,
+    },
+    Events {
+        // only these apps, all if omitted
+        apps: Vec<String>,
+        #[arg(long)]
+        device: Option<String>,
+    },
+    Cp {
+        src: String,
+        dst: String,
+        #[arg(long)]
+        device: Option<String>,
+    },
+    Status {
+        #[arg(long)]
+        device: Option<String>,
+    },
+    Pairing {
+        #[command(subcommand)]
+        action: Option<PairingAction>,
+    },
     Discover,
     Sync {
         range: String,
@@ -56,14 +87,51 @@ enum Cmd {
     Devices {
         #[command(subcommand)]
         action: Option<DeviceAction>,
+        // ask each device for its vitals and apps
+        #[arg(long)]
+        status: bool,
+    },
+    Daemon {
+        #[command(subcommand)]
+        action: DaemonAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum DaemonAction {
+    // replace flared on the device with this binary
+    Upgrade {
+        #[arg(long)]
+        binary: PathBuf,
+        // raw ed25519 signature of the binary, the daemon refuses unsigned ones
+        #[arg(long)]
+        signature: Option<PathBuf>,
+        #[arg(long)]
+        device: Option<String>,
     },
 }
 
+#[derive(Subcommand)]
+enum VolumeAction {
+    // path, size and backups
+    Info { app: String },
+    Backup { app: String },
+    // the newest backup unless one is named
+    Restore { app: String, backup: Option<String> },
+}
+
 #[derive(Subcommand)]
 enum DeviceAction {
     Rm { id: String },
 }
 
+#[derive(Subcommand)]
+enum PairingAction {
+    List,
+    Approve { id: u32 },
+    Reject { id: u32 },
+}
+
 #[derive(Subcommand)]
 enum AuthAction {
     Login,
@@ -88,6 +156,10 @@ async fn main() {
 async fn run(cli: Cli) -> Result<()> {
     use commands::*;
 
+    if cli.local {
+        session::use_local();
+    }
+
     match cli.cmd {
         Cmd::Auth { action } => match action {
             AuthAction::Login => auth::login(),
@@ -115,13 +187,42 @@ async fn run(cli: Cli) -> Result<()> {
         Cmd::Stop { app } => apps::stop(cli.host.clone(), cli.port, app).await,
         Cmd::Restart { app } => apps::restart(cli.host.clone(), cli.port, app).await,
         Cmd::Rollback { app } => apps::rollback(cli.host.clone(), cli.port, app).await,
+        Cmd::Volume { action } => match action {
+            VolumeAction::Info { app } => apps::volume(cli.host, cli.port, app).await,
+            VolumeAction::Backup { app } => apps::backup(cli.host, cli.port, app).await,
+            VolumeAction::Restore { app, backup } => {
+                apps::restore(cli.host, cli.port, app, backup).await
+            }
+        },
+
+        Cmd::Events { apps, device } => events::follow(cli.host, cli.port, device, apps).await,
+
+        Cmd::Cp { src, dst, device } => files::copy(cli.host, cli.port, device, src, dst).await,
+
+        Cmd::Status { device } => status::show(cli.host, cli.port, device).await,
+        Cmd::Pairing { action } => match action {
+            None | Some(PairingAction::List) => status::pairing("list".into(), None).await,
+            Some(PairingAction::Approve { id }) => {
+                status::pairing("approve".into(), Some(id)).await
+            }
+            Some(PairingAction::Reject { id }) => status::pairing("reject".into(), Some(id)).await,
+        },
 
         Cmd::Discover => discovery::discover().await,
         Cmd::Sync { range } => discovery::sync(range).await,
 
-        Cmd::Devices { action } => match action {
+        Cmd::Devices { action, status } => match action {
+            None if status => devices::status().await,
             None => devices::list(),
             Some(DeviceAction::Rm { id }) => devices::remove(&id),
         },
+
+        Cmd::Daemon { action } => match action {
+            DaemonAction::Upgrade {
+                binary,
+                signature,
+                device,
+            } => daemon::upgrade(cli.host, cli.port, device, binary, signature).await,
+        },
     }
 }
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+
+// sent to the daemon in hello
+pub const CLIENT: &str = concat!("flare ", env!("CARGO_PKG_VERSION"));
+
+pub trait Conn: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Conn for T {}
+
+pub type Session = common::Session<Box<dyn Conn>>;
+
+// set by --local: talk to flared on this machine over its admin socket
+static LOCAL: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn use_local() {
+    let path = std::env::var("FLARE_SOCKET")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| common::flare_dir().join("flared.sock"));
+    let _ = LOCAL.set(path);
+}
+
+pub fn is_local() -> bool {
+    LOCAL.get().is_some()
+}
+
+// TCP + TLS + hello (or the local socket), the session can then carry any number of requests
+pub async fn open(host: &str, port: u16) -> Result<Session> {
+    let conn: Box<dyn Conn> = match LOCAL.get() {
+        Some(path) => Box::new(
+            UnixStream::connect(path)
+                .await
+                .map_err(|e| anyhow::anyhow!("Can't connect to {:?}: {}", path, e))?,
+        ),
+        None => {
+            let tcp = TcpStream::connect(format!("{}:{}", host, port)).await?;
+            Box::new(crate::tls::connect(tcp, host).await?)
+        }
+    };
+
+    common::Session::connect(conn, CLIENT).await
+}
@@ -1,11 +1,47 @@
+use crate::{
+    CancelRequest, DownloadRequest, Envelope, HelloRequest, HelloResponse, PROTOCOL_VERSION,
+    Request, Response, TransferAckRequest, TransferTarget, UploadRequest,
+};
 use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, WriteHalf};
+use tokio::sync::{Mutex, mpsc};
 
 // Protocol: [4 byte length][data]
 // Max saze for packege: 10 MB
 
 const MAX_MSG_SIZE: usize = 10 * 1024 * 1024;
 
+// Binary chunk: [4 byte length][0x00][stream u64][seq u64][flags u8][crc32 u32][data]
+// JSON always starts with '{', so both kinds share the same framing
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+// chunks in flight before the sender waits for an ack
+pub const TRANSFER_WINDOW: u64 = 8;
+
+const CHUNK_MAGIC: u8 = 0;
+const CHUNK_HEADER: usize = 1 + 8 + 8 + 1 + 4;
+const FLAG_END: u8 = 1;
+
+#[derive(Debug)]
+pub struct Chunk {
+    pub stream: u64,
+    pub seq: u64,
+    // last chunk of the transfer
+    pub end: bool,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum Frame<T> {
+    Json(T),
+    Chunk(Chunk),
+}
+
 pub async fn send_msg<S>(stream: &mut S, data: &[u8]) -> Result<()>
 where
     S: AsyncWriteExt + Unpin,
@@ -33,6 +69,55 @@ where
     Ok(buf)
 }
 
+pub async fn send_chunk<S>(stream: &mut S, chunk: &Chunk) -> Result<()>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let mut buf = Vec::with_capacity(CHUNK_HEADER + chunk.data.len());
+    buf.push(CHUNK_MAGIC);
+    buf.extend_from_slice(&chunk.stream.to_be_bytes());
+    buf.extend_from_slice(&chunk.seq.to_be_bytes());
+    buf.push(if chunk.end { FLAG_END } else { 0 });
+    buf.extend_from_slice(&crc32fast::hash(&chunk.data).to_be_bytes());
+    buf.extend_from_slice(&chunk.data);
+    send_msg(stream, &buf).await
+}
+
+// JSON message or binary chunk, chunks are checksum-verified
+pub async fn recv_frame<S, T>(stream: &mut S) -> Result<Frame<T>>
+where
+    S: AsyncReadExt + Unpin,
+    T: serde::de::DeserializeOwned,
+{
+    let data = recv_msg(stream).await?;
+    if data.first() != Some(&CHUNK_MAGIC) {
+        return Ok(Frame::Json(serde_json::from_slice(&data)?));
+    }
+
+    if data.len() < CHUNK_HEADER {
+        anyhow::bail!("Chunk too short: {} bytes", data.len());
+    }
+
+    let u64_at = |i: usize| u64::from_be_bytes(data[i..i + 8].try_into().unwrap());
+    let chunk = Chunk {
+        stream: u64_at(1),
+        seq: u64_at(9),
+        end: data[17] & FLAG_END != 0,
+        data: data[CHUNK_HEADER..].to_vec(),
+    };
+
+    let crc = u32::from_be_bytes(data[18..22].try_into().unwrap());
+    if crc32fast::hash(&chunk.data) != crc {
+        anyhow::bail!(
+            "Chunk {} of stream {} failed checksum",
+            chunk.seq,
+            chunk.stream
+        );
+    }
+
+    Ok(Frame::Chunk(chunk))
+}
+
 pub async fn send_json<S, T>(stream: &mut S, data: &T) -> Result<()>
 where
     S: AsyncWriteExt + Unpin,
@@ -50,3 +135,417 @@ where
     let data = recv_msg(stream).await?;
     Ok(serde_json::from_slice(&data)?)
 }
+
+// Client side of the hello exchange, must be the first message on a connection
+pub async fn hello<S>(stream: &mut S, client: &str) -> Result<HelloResponse>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let req = Request::Hello(HelloRequest {
+        version: PROTOCOL_VERSION,
+        client: client.to_string(),
+        capabilities: Vec::new(),
+    });
+
+    send_json(stream, &req).await?;
+    match recv_json(stream).await? {
+        Response::Hello(resp) => Ok(resp),
+        Response::Error(e) => Err(e.into()),
+        other => anyhow::bail!("Unexpected hello response: {:?}", other),
+    }
+}
+
+// Long-lived control connection, requests are multiplexed by id after hello
+pub struct Session<S> {
+    writer: Mutex<WriteHalf<S>>,
+    pending: Pending,
+    next_id: AtomicU64,
+    pub daemon: HelloResponse,
+}
+
+type Pending = Arc<std::sync::Mutex<HashMap<u64, mpsc::UnboundedSender<Frame<Response>>>>>;
+
+impl<S> Session<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    pub async fn connect(mut stream: S, client: &str) -> Result<Self> {
+        let daemon = hello(&mut stream, client).await?;
+        let (reader, writer) = tokio::io::split(stream);
+
+        let pending: Pending = Arc::default();
+        tokio::spawn(read_loop(reader, pending.clone()));
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            pending,
+            next_id: AtomicU64::new(1),
+            daemon,
+        })
+    }
+
+    // Single request -> single response, protocol errors from the daemon come back as Err
+    pub async fn call(&self, req: Request) -> Result<Response> {
+        let mut stream = self.stream(req).await?;
+        stream
+            .next()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Session closed"))?
+    }
+
+    // Request answered with a stream of responses (subscribe)
+    pub async fn stream(&self, req: Request) -> Result<ResponseStream> {
+        if !self.daemon.capabilities.iter().any(|c| c == req.kind()) {
+            anyhow::bail!(
+                "Daemon {} doesn't support '{}', upgrade flared",
+                self.daemon.daemon,
+                req.kind()
+            );
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.pending.lock().unwrap().insert(id, tx);
+
+        if let Err(e) = self.send(id, req).await {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+
+        Ok(ResponseStream { id, rx })
+    }
+
+    // Stop a stream started by this session, the stream itself gets StreamEnd.
+    // Cancel has no response of its own.
+    pub async fn cancel(&self, stream: &ResponseStream) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.send(id, Request::Cancel(CancelRequest { stream: stream.id }))
+            .await
+    }
+
+    // Send a file, resumes where a previous attempt with the same transfer id stopped
+    pub async fn upload(
+        &self,
+        transfer: &str,
+        target: TransferTarget,
+        path: &Path,
+        daemon_token: Option<String>,
+    ) -> Result<u64> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+
+        let req = Request::Upload(UploadRequest {
+            transfer: transfer.to_string(),
+            target,
+            size,
+            daemon_token,
+        });
+        let mut stream = self.stream(req).await?;
+
+        let mut sent = match stream.next().await {
+            Some(Ok(Response::TransferReady(r))) => r.offset,
+            Some(Err(e)) => return Err(e),
+            other => anyhow::bail!("Unexpected response: {:?}", other),
+        };
+        file.seek(SeekFrom::Start(sent)).await?;
+
+        let window = TRANSFER_WINDOW * CHUNK_SIZE as u64;
+        let mut acked = sent;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+
+        for seq in 0.. {
+            while sent - acked >= window {
+                match stream.next().await {
+                    Some(Ok(Response::TransferAck(a))) => acked = a.offset,
+                    Some(Err(e)) => return Err(e),
+                    other => anyhow::bail!("Unexpected response: {:?}", other),
+                }
+            }
+
+            let n = file.read(&mut buf).await?;
+            if n == 0 && sent < size {
+                anyhow::bail!("{:?} shrank during upload", path);
+            }
+            sent += n as u64;
+
+            let chunk = Chunk {
+                stream: stream.id,
+                seq,
+                end: sent >= size,
+                data: buf[..n].to_vec(),
+            };
+            send_chunk(&mut *self.writer.lock().await, &chunk).await?;
+
+            if chunk.end {
+                break;
+            }
+        }
+
+        loop {
+            match stream.next().await {
+                Some(Ok(Response::TransferAck(_))) => continue,
+                Some(Ok(Response::TransferDone(d))) => return Ok(d.size),
+                Some(Err(e)) => return Err(e),
+                other => anyhow::bail!("Unexpected response: {:?}", other),
+            }
+        }
+    }
+
+    // Fetch a file into `dest`, partial data is kept in `<dest>.part` and resumed
+    pub async fn download(
+        &self,
+        target: TransferTarget,
+        dest: &Path,
+        daemon_token: Option<String>,
+    ) -> Result<u64> {
+        let part = PathBuf::from(format!("{}.part", dest.display()));
+        let offset = tokio::fs::metadata(&part)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0);
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part)
+            .await?;
+
+        let req = Request::Download(DownloadRequest {
+            target,
+            offset,
+            daemon_token,
+        });
+        let mut stream = self.stream(req).await?;
+
+        let (mut received, size) = match stream.next().await {
+            Some(Ok(Response::TransferReady(r))) => (r.offset, r.size),
+            Some(Err(e)) => return Err(e),
+            other => anyhow::bail!("Unexpected response: {:?}", other),
+        };
+        if received != offset {
+            // daemon's file changed, start over
+            file.set_len(received).await?;
+        }
+
+        let mut seq = 0;
+        loop {
+            let chunk = match stream.next_frame().await {
+                Some(Ok(Frame::Chunk(c))) => c,
+                Some(Ok(Frame::Json(r))) => anyhow::bail!("Unexpected response: {:?}", r),
+                Some(Err(e)) => return Err(e),
+                None => anyhow::bail!("Transfer interrupted at {} of {} bytes", received, size),
+            };
+
+            if chunk.seq != seq {
+                anyhow::bail!("Chunk out of order: expected {}, got {}", seq, chunk.seq);
+            }
+            seq += 1;
+
+            file.write_all(&chunk.data).await?;
+            received += chunk.data.len() as u64;
+
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let ack = TransferAckRequest {
+                stream: stream.id,
+                offset: received,
+            };
+            self.send(id, Request::TransferAck(ack)).await?;
+
+            if chunk.end {
+                break;
+            }
+        }
+
+        file.sync_all().await?;
+        match stream.next().await {
+            Some(Ok(Response::TransferDone(d))) if d.size == received => {}
+            Some(Ok(Response::TransferDone(d))) => {
+                anyhow::bail!("Size mismatch: got {} of {} bytes", received, d.size)
+            }
+            Some(Err(e)) => return Err(e),
+            other => anyhow::bail!("Unexpected response: {:?}", other),
+        }
+
+        tokio::fs::rename(&part, dest).await?;
+        Ok(received)
+    }
+
+    async fn send(&self, id: u64, msg: Request) -> Result<()> {
+        send_json(&mut *self.writer.lock().await, &Envelope { id, msg }).await
+    }
+}
+
+pub struct ResponseStream {
+    id: u64,
+    rx: mpsc::UnboundedReceiver<Frame<Response>>,
+}
+
+impl ResponseStream {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // None once the stream has ended or the session is gone
+    pub async fn next(&mut self) -> Option<Result<Response>> {
+        loop {
+            match self.next_frame().await? {
+                Ok(Frame::Json(resp)) => return Some(Ok(resp)),
+                Ok(Frame::Chunk(c)) => {
+                    tracing::warn!("Dropping unexpected chunk of stream {}", c.stream)
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    // Like next, but also yields binary chunks (download)
+    pub async fn next_frame(&mut self) -> Option<Result<Frame<Response>>> {
+        match self.rx.recv().await? {
+            Frame::Json(Response::StreamEnd) => None,
+            Frame::Json(Response::Error(e)) => Some(Err(e.into())),
+            frame => Some(Ok(frame)),
+        }
+    }
+}
+
+async fn read_loop<R>(mut reader: R, pending: Pending)
+where
+    R: AsyncReadExt + Unpin,
+{
+    while let Ok(frame) = recv_frame::<_, Envelope<Response>>(&mut reader).await {
+        let mut pending = pending.lock().unwrap();
+
+        let envelope = match frame {
+            Frame::Json(e) => e,
+            Frame::Chunk(chunk) => {
+                if let Some(tx) = pending.get(&chunk.stream) {
+                    let _ = tx.send(Frame::Chunk(chunk));
+                }
+                continue;
+            }
+        };
+
+        let done = !envelope.msg.is_stream_item();
+        if let Some(tx) = pending.get(&envelope.id) {
+            let _ = tx.send(Frame::Json(envelope.msg));
+        }
+        if done {
+            pending.remove(&envelope.id);
+        }
+    }
+
+    // wake everyone still waiting, their receivers see a closed channel
+    pending.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TransferReady, TransferTarget};
+
+    fn chunk(seq: u64, data: &[u8]) -> Chunk {
+        Chunk {
+            stream: 7,
+            seq,
+            end: false,
+            data: data.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_and_json_round_trip() {
+        let mut wire = Vec::new();
+        let sent = Chunk {
+            end: true,
+            ..chunk(3, b"hello")
+        };
+        send_chunk(&mut wire, &sent).await.unwrap();
+        send_json(
+            &mut wire,
+            &TransferAckRequest {
+                stream: 7,
+                offset: 5,
+            },
+        )
+        .await
+        .unwrap();
+
+        let mut wire = wire.as_slice();
+        match recv_frame::<_, TransferAckRequest>(&mut wire)
+            .await
+            .unwrap()
+        {
+            Frame::Chunk(c) => {
+                assert_eq!((c.stream, c.seq, c.end), (7, 3, true));
+                assert_eq!(c.data, b"hello");
+            }
+            Frame::Json(j) => panic!("got json {:?}", j),
+        }
+        match recv_frame::<_, TransferAckRequest>(&mut wire)
+            .await
+            .unwrap()
+        {
+            Frame::Json(ack) => assert_eq!((ack.stream, ack.offset), (7, 5)),
+            Frame::Chunk(c) => panic!("got chunk {}", c.seq),
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupted_chunk_fails_checksum() {
+        let mut wire = Vec::new();
+        send_chunk(&mut wire, &chunk(0, b"hello")).await.unwrap();
+        *wire.last_mut().unwrap() ^= 1;
+
+        let e = recv_frame::<_, Response>(&mut wire.as_slice())
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("failed checksum"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn download_refuses_chunks_out_of_order() {
+        let (client, mut daemon) = tokio::io::duplex(CHUNK_SIZE);
+        tokio::spawn(async move {
+            let _: Request = recv_json(&mut daemon).await.unwrap();
+            let hello = HelloResponse {
+                version: PROTOCOL_VERSION,
+                daemon: "test".into(),
+                capabilities: vec!["download".into()],
+            };
+            send_json(&mut daemon, &Response::Hello(hello))
+                .await
+                .unwrap();
+
+            let req: Envelope<Request> = recv_json(&mut daemon).await.unwrap();
+            let ready = Response::TransferReady(TransferReady { offset: 0, size: 4 });
+            send_json(
+                &mut daemon,
+                &Envelope {
+                    id: req.id,
+                    msg: ready,
+                },
+            )
+            .await
+            .unwrap();
+            for seq in [0, 2] {
+                let c = Chunk {
+                    stream: req.id,
+                    ..chunk(seq, b"ab")
+                };
+                send_chunk(&mut daemon, &c).await.unwrap();
+            }
+            // acks until the client hangs up
+            while recv_msg(&mut daemon).await.is_ok() {}
+        });
+
+        let tmp = tempfile::tempdir().unwrap();
+        let session = Session::connect(client, "test").await.unwrap();
+        let target = TransferTarget::DaemonBinary;
+        let e = session
+            .download(target, &tmp.path().join("file"), None)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("expected 1, got 2"), "{}", e);
+        assert!(!tmp.path().join("file").exists());
+    }
+}
@@ -1,9 +1,137 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+// Bumped on every incompatible protocol change
+pub const PROTOCOL_VERSION: u32 = 2;
+// Oldest client/daemon protocol we still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+// Every message after hello is wrapped in an envelope, responses carry the id of their request.
+// `id` is taken by the envelope, messages must not have a field with that name
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub id: u64,
+    #[serde(flatten)]
+    pub msg: T,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "msg_type", rename_all = "snake_case")]
+pub enum Request {
+    Hello(HelloRequest),
+    RegisterToken(RegisterTokenRequest),
+    Deploy(DeployRequest),
+    Manage(ManageRequest),
+    Subscribe(SubscribeRequest),
+    Cancel(CancelRequest),
+    Upload(UploadRequest),
+    Download(DownloadRequest),
+    TransferAck(TransferAckRequest),
+    Status(StatusRequest),
+    // local socket only
+    Pairing(PairingRequest),
+    Upgrade(UpgradeRequest),
+}
+
+impl Request {
+    // wire name, also used as capability name
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Hello(_) => "hello",
+            Request::RegisterToken(_) => "register_token",
+            Request::Deploy(_) => "deploy",
+            Request::Manage(_) => "manage",
+            Request::Subscribe(_) => "subscribe",
+            Request::Cancel(_) => "cancel",
+            Request::Upload(_) => "upload",
+            Request::Download(_) => "download",
+            Request::TransferAck(_) => "transfer_ack",
+            Request::Status(_) => "status",
+            Request::Pairing(_) => "pairing",
+            Request::Upgrade(_) => "upgrade",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "msg_type", rename_all = "snake_case")]
+pub enum Response {
+    Hello(HelloResponse),
+    RegisterToken(RegisterTokenResponse),
+    Deploy(DeployResponse),
+    Manage(ManageResponse),
+    Event(Event),
+    // last message of a stream (subscribe)
+    StreamEnd,
+    TransferReady(TransferReady),
+    TransferAck(TransferAck),
+    TransferDone(TransferDone),
+    Status(StatusResponse),
+    Pairing(PairingResponse),
+    Upgrade(UpgradeResponse),
+    Error(ErrorResponse),
+}
+
+impl Response {
+    // streamed items keep the request open, anything else completes it
+    pub fn is_stream_item(&self) -> bool {
+        matches!(
+            self,
+            Response::Event(_) | Response::TransferReady(_) | Response::TransferAck(_)
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloRequest {
+    pub version: u32,
+    pub client: String,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloResponse {
+    pub version: u32,
+    pub daemon: String,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    HandshakeRequired,
+    UnsupportedVersion,
+    UnknownRequest,
+    BadRequest,
+    Unauthorized,
+    Internal,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:?})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeployRequest {
-    pub msg_type: String,
     pub repo: String,
     pub forge: String,
     pub auth_user: Option<String>,
@@ -20,9 +148,13 @@ pub struct DeployResponse {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ManageRequest {
-    pub msg_type: String, // "manage"
     pub app: String,
-    pub action: String, // "start", "stop", "restart"
+    pub action: String, // "start", "stop", "restart", "rollback", "volume", "backup", "restore"
+    // backup to restore, the newest if not set
+    #[serde(default)]
+    pub arg: Option<String>,
+    #[serde(default)]
+    pub daemon_token: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,6 +163,79 @@ pub struct ManageResponse {
     pub message: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscribeRequest {
+    // only events of these apps, all if empty
+    #[serde(default)]
+    pub apps: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelRequest {
+    // id of the request to cancel
+    pub stream: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Event {
+    pub kind: String, // "deploy_started", "deploy_finished", "deploy_failed", "app_started", ...
+    pub app: String,
+    pub message: String,
+    pub timestamp: i64,
+}
+
+// Binary transfers: data goes in chunk frames, the request id doubles as their stream id
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransferTarget {
+    // file relative to the app directory
+    AppFile { app: String, path: String },
+    // new flared binary, installed by a following upgrade request
+    DaemonBinary,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadRequest {
+    // stable across reconnects, lets the daemon resume a partial upload
+    pub transfer: String,
+    pub target: TransferTarget,
+    pub size: u64,
+    pub daemon_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadRequest {
+    pub target: TransferTarget,
+    // bytes the client already has
+    pub offset: u64,
+    pub daemon_token: Option<String>,
+}
+
+// receiver -> sender, no response
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferAckRequest {
+    // id of the download request
+    pub stream: u64,
+    pub offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferReady {
+    // data starts here, > 0 when resuming
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferAck {
+    pub offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferDone {
+    pub size: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppState {
     pub name: String,
@@ -40,6 +245,18 @@ pub struct AppState {
     pub port: Option<u16>,
     pub health_url: Option<String>,
     pub isolation: Option<String>,
+    // times the kernel killed it for going over resource_limits.memory
+    #[serde(default)]
+    pub oom_kills: u64,
+    // commit the running release was built from, when the archive says
+    #[serde(default)]
+    pub commit: Option<String>,
+    // last health check verdict: healthy, unhealthy or degraded
+    #[serde(default)]
+    pub health: Option<String>,
+    // unix time of the deploy that produced the running release
+    #[serde(default)]
+    pub deployed_at: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -70,12 +287,20 @@ pub struct AppSection {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BuildSection {
     pub command: String,
+    // "90s", "10m", "1h"; default [resource_limits] timeout
+    pub timeout: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RunSection {
     pub command: String,
     pub port: Option<u16>,
+    // run (and build) as this user/group instead of flared's own
+    pub user: Option<String>,
+    pub group: Option<String>,
+    // as a dedicated system user "flare-<app>", created on first deploy
+    #[serde(default)]
+    pub create_user: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -86,14 +311,42 @@ pub struct WebSection {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HealthSection {
-    pub url: String,
+    // "http", "tcp" or "command", default: command, else url, else tcp
+    pub r#type: Option<String>,
+    pub url: Option<String>,
+    // tcp, default [run] port
+    pub port: Option<u16>,
+    pub command: Option<String>,
+    // seconds between checks, and for one check
+    pub interval: Option<u64>,
     pub timeout: Option<u64>,
+    // failed checks in a row before on_failure
+    pub failure_threshold: Option<u32>,
+    // "restart" (default), "degraded" or "rollback"
+    pub on_failure: Option<String>,
+    // seconds a new release gets to pass a check before its deploy is aborted
+    pub ready_timeout: Option<u64>,
+    #[serde(default)]
     pub auto_add: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IsolationSection {
+    // systemd or sandbox, empty: none
+    #[serde(default)]
     pub r#type: String,
+    // sandbox: "host" (default) or "private", its own network reachable through `ports`
+    pub network: Option<String>,
+    // sandbox with a private network: "8080:3000" (host:app) or "3000", default [run] port
+    #[serde(default)]
+    pub ports: Vec<String>,
+    // sandbox: default syscall filter, on unless false
+    pub seccomp: Option<bool>,
+    // other types: Landlock limits the app to these (plus its own directory)
+    #[serde(default)]
+    pub allow_read: Vec<String>,
+    #[serde(default)]
+    pub allow_write: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -101,6 +354,8 @@ pub struct StorageSection {
     pub r#type: String,
     pub bucket: Option<String>,
     pub endpoint: Option<String>,
+    // s3, default us-east-1
+    pub region: Option<String>,
     pub access_key: Option<String>,
     pub secret_key: Option<String>,
     pub size: Option<String>,
@@ -120,8 +375,18 @@ pub struct DatabaseSection {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NotifySection {
+    // targets: URL (JSON webhook), slack:, discord:, matrix:, ntfy:, mailto:
     pub on_success: Option<Vec<String>>,
     pub on_fail: Option<Vec<String>>,
+    // crash loops, default on_fail
+    pub on_crash: Option<Vec<String>>,
+    // health changes, default on_fail
+    pub on_health: Option<Vec<String>>,
+    // event -> message, with {app}, {version}, {commit}, {device}, {event}, {message}
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+    // attempts per target
+    pub retries: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -134,6 +399,8 @@ pub struct SecretsSection {
 pub struct ResourceLimitsSection {
     pub memory: Option<String>,
     pub cpu: Option<String>,
+    pub pids: Option<u64>,
+    // build, hooks and other one-shot commands
     pub timeout: Option<String>,
 }
 
@@ -141,12 +408,26 @@ pub struct ResourceLimitsSection {
 pub struct HooksSection {
     pub pre_deploy: Option<String>,
     pub post_deploy: Option<String>,
+    pub pre_build: Option<String>,
+    pub post_build: Option<String>,
+    pub pre_start: Option<String>,
+    pub post_start: Option<String>,
+    pub pre_stop: Option<String>,
+    pub on_rollback: Option<String>,
+    pub on_health_fail: Option<String>,
+    // per hook, default [resource_limits] timeout
+    pub timeout: Option<String>,
+    // a failing hook only gets logged instead of failing the deploy
+    #[serde(default)]
+    pub continue_on_error: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MetricsSection {
     pub pushgateway: Option<String>,
     pub collect: Option<Vec<String>>,
+    // seconds between pushes
+    pub interval: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -173,11 +454,85 @@ pub struct FlareConfig {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterTokenRequest {
-    pub msg_type: String,
     pub token_hash: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterTokenResponse {
     pub success: bool,
+    // token works only after approval on the device (flare --local pairing approve)
+    #[serde(default)]
+    pub pending: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusRequest {
+    pub daemon_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusResponse {
+    pub daemon: String,
+    pub apps: Vec<AppState>,
+    // older daemons don't send it
+    #[serde(default)]
+    pub device: Option<DeviceStatus>,
+}
+
+// host vitals, also served as JSON on the health server's /status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceStatus {
+    pub hostname: String,
+    // seconds
+    pub uptime: u64,
+    pub daemon_uptime: u64,
+    // 1, 5 and 15 minute load averages
+    pub load: [f64; 3],
+    pub cpus: u32,
+    // bytes
+    pub memory_total: u64,
+    pub memory_available: u64,
+    // filesystem holding the apps
+    pub disk_total: u64,
+    pub disk_free: u64,
+    // °C, hottest thermal zone, where the board has any
+    pub temperature: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PairingRequest {
+    pub action: String,     // "list", "approve", "reject"
+    pub token: Option<u32>, // PendingToken::id
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PairingResponse {
+    pub message: String,
+    pub pending: Vec<PendingToken>,
+}
+
+// token registered over the network, waiting for approval on the device
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PendingToken {
+    pub id: u32,
+    pub peer: String,
+    pub requested: i64,
+    pub token_hash: String,
+}
+
+// sent after uploading the binary to TransferTarget::DaemonBinary
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpgradeRequest {
+    pub sha256: String, // hex
+    // hex ed25519 signature of the binary, always required: the daemon refuses
+    // unsigned binaries and upgrades without upgrade.public_key
+    pub signature: Option<String>,
+    pub daemon_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpgradeResponse {
+    pub message: String,
+    // as the new binary reports it, the daemon restarts right after this response
+    pub version: String,
 }
@@ -3,7 +3,7 @@ use anyhow::{Ok, Result};
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use rand::RngCore;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub fn flare_dir() -> PathBuf {
     // let home = std::env::var("HOME").unwrap_or_else(|_| "/tmp".into());
@@ -23,10 +23,69 @@ pub fn app_dir(name: &str) -> PathBuf {
 
 pub fn save_state(dir: &PathBuf, state: &AppState) -> Result<()> {
     let content = toml::to_string_pretty(state)?;
-    std::fs::write(dir.join("state.toml"), content)?;
+    replace_file(&dir.join("state.toml"), content.as_bytes())?;
     Ok(())
 }
 
+// Writes a temp file and renames it over `path`: readers never see half a
+// file, and whatever was at `path` (say a symlink the app put there) is
+// replaced rather than written through.
+pub fn replace_file(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_file(&tmp);
+    let result = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+        .and_then(|mut f| f.write_all(content))
+        .and_then(|_| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+// `rel` below `dir`, for paths from apps and clients that flared then writes
+// as root: nothing absolute, no "..", and no directory on the way may be a
+// symlink (the release is the app's to change). Missing directories are
+// created with `create`. The last component is the caller's to handle.
+pub fn path_inside(dir: &Path, rel: &Path, create: bool) -> Result<PathBuf> {
+    use std::path::Component;
+
+    let parts: Vec<_> = rel
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect();
+    if parts.is_empty() || !parts.iter().all(|c| matches!(c, Component::Normal(_))) {
+        anyhow::bail!("{:?} is not a path inside {:?}", rel, dir);
+    }
+
+    let mut path = dir.to_path_buf();
+    for part in &parts[..parts.len() - 1] {
+        path.push(part);
+        match std::fs::symlink_metadata(&path) {
+            Result::Ok(m) if m.is_dir() => {}
+            Result::Ok(_) => anyhow::bail!("{:?} is a symlink or a file, not a directory", path),
+            Err(e) if create && e.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::create_dir(&path)?
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    path.push(parts[parts.len() - 1]);
+    Ok(path)
+}
+
 pub fn load_state(dir: &PathBuf) -> Result<Option<AppState>> {
     let path = dir.join("state.toml");
     if !path.exists() {
@@ -107,6 +166,22 @@ pub fn hash_token(token: &str) -> Result<String> {
     Ok(hash.to_string())
 }
 
+pub fn sha256_file(path: &Path) -> Result<String> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        ctx.update(&buf[..n]);
+    }
+    Ok(hex::encode(ctx.finish()))
+}
+
 pub fn verify_token(token: &str, hash: &str) -> bool {
     let parsed_hash = match PasswordHash::new(hash) {
         Ok(h) => h,
@@ -0,0 +1,228 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+use tracing::{info, warn};
+
+use crate::server::{HealthPids, Routes};
+
+const PIDFILE: &str = "flare.pid";
+
+// Identity of a started app process. A PID alone can be recycled,
+// pid + boot + start time can't, cmdline catches the rest.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct PidFile {
+    pub pid: u32,
+    pub boot_id: String,
+    // clock ticks since boot, /proc/<pid>/stat field 22
+    pub start_time: u64,
+    pub cmdline: Vec<String>,
+}
+
+impl PidFile {
+    pub fn read_proc(pid: u32) -> Result<Self> {
+        let start_time = crate::process::stat(pid)?[19]
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Bad /proc/{}/stat", pid))?;
+
+        let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid))?
+            .split(|b| *b == 0)
+            .filter(|a| !a.is_empty())
+            .map(|a| String::from_utf8_lossy(a).into_owned())
+            .collect();
+
+        Ok(Self {
+            pid,
+            boot_id: boot_id(),
+            start_time,
+            cmdline,
+        })
+    }
+
+    // same process as recorded; `sh -c` may have exec'd the command since
+    fn matches(&self, now: &PidFile) -> bool {
+        if self.boot_id != now.boot_id || self.start_time != now.start_time {
+            return false;
+        }
+        if self.cmdline == now.cmdline {
+            return true;
+        }
+
+        // program now running must be one of the words we spawned
+        let name = |w: &str| Path::new(w).file_name().map(|n| n.to_os_string());
+        now.cmdline.first().is_some_and(|prog| {
+            self.cmdline
+                .iter()
+                .flat_map(|a| a.split_whitespace())
+                .any(|w| name(w) == name(prog))
+        })
+    }
+}
+
+fn boot_id() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+// cmdline is taken from what we spawned, /proc may not show it yet this early
+pub fn record(dir: &Path, pid: u32, cmd: &Command) {
+    let cmdline = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect();
+
+    let result = PidFile::read_proc(pid).and_then(|p| {
+        let p = PidFile { cmdline, ..p };
+        Ok(common::replace_file(
+            &dir.join(PIDFILE),
+            toml::to_string(&p)?.as_bytes(),
+        )?)
+    });
+    if let Err(e) = result {
+        warn!("Can't write pidfile for PID {}: {}", pid, e);
+    }
+}
+
+pub fn forget(dir: &Path) {
+    let _ = std::fs::remove_file(dir.join(PIDFILE));
+}
+
+// pidfile as is, for put_back() when a replacement doesn't work out
+pub fn saved(dir: &Path) -> Option<String> {
+    std::fs::read_to_string(dir.join(PIDFILE)).ok()
+}
+
+pub fn put_back(dir: &Path, saved: Option<String>) {
+    match saved {
+        Some(content) => {
+            if let Err(e) = common::replace_file(&dir.join(PIDFILE), content.as_bytes()) {
+                warn!("Can't restore pidfile in {:?}: {}", dir, e);
+            }
+        }
+        None => forget(dir),
+    }
+}
+
+fn still_ours(dir: &Path, pid: u32) -> bool {
+    let recorded: PidFile = match std::fs::read_to_string(dir.join(PIDFILE))
+        .ok()
+        .and_then(|c| toml::from_str(&c).ok())
+    {
+        Some(p) => p,
+        // no pidfile, can't tell it from a recycled PID
+        None => return false,
+    };
+
+    recorded.pid == pid
+        && PidFile::read_proc(pid)
+            .map(|now| recorded.matches(&now))
+            .unwrap_or(false)
+}
+
+// On startup: take back apps that survived the restart, mark the others lost
+pub async fn adopt_all(routes: &Routes, health_pids: &HealthPids) {
+    let apps_dir = crate::config::get().apps_dir();
+    let entries = match std::fs::read_dir(&apps_dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let dir = entry.path();
+        let mut state = match common::load_state(&dir) {
+            Ok(Some(s)) => s,
+            _ => continue,
+        };
+        if state.status != "running" {
+            continue;
+        }
+
+        let Some(pid) = state.pid else {
+            // static site, nothing to check, just serve it again
+            if let Ok(config) = common::load_app_config(&dir)
+                && let Some(web) = &config.web
+            {
+                let root = dir.join(web.root.as_deref().unwrap_or("."));
+                routes
+                    .write()
+                    .await
+                    .static_sites
+                    .insert(web.domain.clone(), root.to_string_lossy().into());
+                info!("Re-adopted static site {}", state.name);
+            }
+            continue;
+        };
+
+        if still_ours(&dir, pid) {
+            info!("Re-adopted {} (PID {})", state.name, pid);
+            crate::health_server::update_pid(health_pids, &state.name, Some(pid)).await;
+            if let Ok(config) = common::load_app_config(&dir) {
+                crate::health::watch(&dir, &config, false);
+            }
+            continue;
+        }
+
+        warn!(
+            "{} (PID {}) no longer running, marking lost",
+            state.name, pid
+        );
+        state.status = "lost".into();
+        state.pid = None;
+        forget(&dir);
+        if let Err(e) = common::save_state(&dir, &state) {
+            warn!("Can't save state of {}: {}", state.name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawned(cmdline: &[&str]) -> PidFile {
+        PidFile {
+            pid: 42,
+            boot_id: "boot".into(),
+            start_time: 1000,
+            cmdline: cmdline.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn same_process() {
+        let recorded = spawned(&["/usr/bin/node", "server.js"]);
+        assert!(recorded.matches(&spawned(&["/usr/bin/node", "server.js"])));
+    }
+
+    #[test]
+    fn recycled_pid_doesnt_match() {
+        let recorded = spawned(&["/usr/bin/node", "server.js"]);
+        let rebooted = PidFile {
+            boot_id: "other".into(),
+            ..spawned(&["/usr/bin/node", "server.js"])
+        };
+        assert!(!recorded.matches(&rebooted));
+        let later = PidFile {
+            start_time: 2000,
+            ..spawned(&["/usr/bin/node", "server.js"])
+        };
+        assert!(!recorded.matches(&later));
+        assert!(!recorded.matches(&spawned(&["/usr/sbin/sshd"])));
+    }
+
+    #[test]
+    fn shell_may_exec_the_command() {
+        let recorded = spawned(&["sh", "-c", "exec python3 -m http.server 8080"]);
+        assert!(recorded.matches(&spawned(&["/usr/bin/python3", "-m", "http.server"])));
+        assert!(!recorded.matches(&spawned(&["/usr/bin/perl", "x.pl"])));
+        assert!(!recorded.matches(&spawned(&[])));
+    }
+
+    #[test]
+    fn reads_this_process() {
+        let me = PidFile::read_proc(std::process::id()).unwrap();
+        assert!(!me.boot_id.is_empty());
+        assert!(me.matches(&PidFile::read_proc(std::process::id()).unwrap()));
+    }
+}
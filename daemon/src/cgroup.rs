@@ -0,0 +1,220 @@
+use anyhow::Result;
+use common::ResourceLimitsSection;
+use std::fs::OpenOptions;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::warn;
+
+// cpu.max period, µs
+const CPU_PERIOD: u64 = 100_000;
+const CONTROLLERS: &str = "+memory +cpu +pids";
+
+// [resource_limits] in kernel units
+#[derive(Debug, Default)]
+pub struct Limits {
+    pub memory: Option<u64>, // bytes
+    pub cpu: Option<f64>,    // cores
+    pub pids: Option<u64>,
+}
+
+impl Limits {
+    pub fn parse(section: Option<&ResourceLimitsSection>) -> Result<Self> {
+        let Some(section) = section else {
+            return Ok(Self::default());
+        };
+        Ok(Self {
+            memory: section.memory.as_deref().map(parse_size).transpose()?,
+            cpu: section.cpu.as_deref().map(parse_cpu).transpose()?,
+            pids: section.pids,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.cpu.is_none() && self.pids.is_none()
+    }
+
+    // same limits as systemd-run properties
+    pub fn systemd_properties(&self) -> Vec<String> {
+        let mut props = Vec::new();
+        if let Some(memory) = self.memory {
+            props.push(format!("MemoryMax={}", memory));
+        }
+        if let Some(cpu) = self.cpu {
+            props.push(format!("CPUQuota={}%", (cpu * 100.0).round() as u64));
+        }
+        if let Some(pids) = self.pids {
+            props.push(format!("TasksMax={}", pids));
+        }
+        props
+    }
+}
+
+// "512MB", "512M", "1.5G", "1048576"; binary units like systemd
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        _ => anyhow::bail!("Bad size: {}", s),
+    };
+    let num: f64 = num
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Bad size: {}", s))?;
+    Ok((num * (1u64 << shift) as f64) as u64)
+}
+
+// cores ("1.0", "0.5") or percent of one core ("50%")
+fn parse_cpu(s: &str) -> Result<f64> {
+    let s = s.trim();
+    let cpu = match s.strip_suffix('%') {
+        Some(pct) => pct.trim().parse::<f64>().map(|p| p / 100.0),
+        None => s.parse(),
+    }
+    .map_err(|_| anyhow::anyhow!("Bad cpu limit: {}", s))?;
+
+    if !cpu.is_finite() || cpu <= 0.0 {
+        anyhow::bail!("Bad cpu limit: {}", s);
+    }
+    Ok(cpu)
+}
+
+// one cgroup per app directory
+pub fn path(dir: &Path) -> PathBuf {
+    let name = dir.file_name().unwrap_or_default();
+    crate::config::get().cgroups.root.join(name)
+}
+
+// Sets the app cgroup up and makes `cmd` join it right before exec,
+// so nothing it forks can escape the limits.
+pub fn apply(cmd: &mut Command, dir: &Path, limits: &Limits) -> Result<()> {
+    let root = &crate::config::get().cgroups.root;
+    enable_controllers(root).map_err(|e| {
+        anyhow::anyhow!(
+            "Can't set up cgroup {:?}: {} (try isolation = \"systemd\")",
+            root,
+            e
+        )
+    })?;
+
+    let cgroup = path(dir);
+    std::fs::create_dir_all(&cgroup)?;
+
+    let memory = limits.memory.map(|m| m.to_string());
+    let cpu = limits
+        .cpu
+        .map(|c| format!("{} {}", (c * CPU_PERIOD as f64) as u64, CPU_PERIOD));
+    let pids = limits.pids.map(|p| p.to_string());
+    // "max" lifts limits dropped from the config since the last start
+    write(&cgroup, "memory.max", memory.as_deref().unwrap_or("max"))?;
+    write(&cgroup, "cpu.max", cpu.as_deref().unwrap_or("max"))?;
+    write(&cgroup, "pids.max", pids.as_deref().unwrap_or("max"))?;
+
+    // opened here, the child only gets to write(2) between fork and exec
+    let procs = OpenOptions::new()
+        .write(true)
+        .open(cgroup.join("cgroup.procs"))?;
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+fn enable_controllers(root: &Path) -> Result<()> {
+    let parent = root
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Bad cgroup root"))?;
+    if !parent.join("cgroup.controllers").exists() {
+        anyhow::bail!("No cgroup v2 hierarchy at {:?}", parent);
+    }
+
+    std::fs::create_dir_all(root)?;
+    // usually on already, fails harmlessly when it is
+    let _ = std::fs::write(parent.join("cgroup.subtree_control"), CONTROLLERS);
+    std::fs::write(root.join("cgroup.subtree_control"), CONTROLLERS)?;
+    Ok(())
+}
+
+fn write(cgroup: &Path, file: &str, value: &str) -> Result<()> {
+    std::fs::write(cgroup.join(file), value)
+        .map_err(|e| anyhow::anyhow!("Can't write {} = {}: {}", file, value, e))
+}
+
+// OOM kills in the app cgroup, or in the scope systemd-run put the process in
+pub fn oom_kills(dir: &Path, pid: Option<u32>) -> Option<u64> {
+    let own = path(dir);
+    let cgroup = if own.exists() {
+        own
+    } else {
+        let line = std::fs::read_to_string(format!("/proc/{}/cgroup", pid?)).ok()?;
+        // "0::/user.slice/...", the v2 entry
+        let rel = line.lines().find_map(|l| l.strip_prefix("0::"))?;
+        Path::new("/sys/fs/cgroup").join(rel.trim_start_matches('/'))
+    };
+
+    let events = std::fs::read_to_string(cgroup.join("memory.events")).ok()?;
+    events
+        .lines()
+        .find_map(|l| l.strip_prefix("oom_kill "))
+        .and_then(|n| n.trim().parse().ok())
+}
+
+// after a status read: new OOM kills get logged and saved with the app state
+pub fn refresh_oom(dir: &PathBuf, state: &mut common::AppState) {
+    let Some(kills) = oom_kills(dir, state.pid) else {
+        return;
+    };
+    if kills > state.oom_kills {
+        warn!(
+            "{} was OOM-killed {} time(s)",
+            state.name,
+            kills - state.oom_kills
+        );
+        state.oom_kills = kills;
+        if let Err(e) = common::save_state(dir, state) {
+            warn!("Can't save state of {}: {}", state.name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1048576").unwrap(), 1 << 20);
+        assert_eq!(parse_size("512MB").unwrap(), 512 << 20);
+        assert_eq!(parse_size("512m").unwrap(), 512 << 20);
+        assert_eq!(parse_size(" 64 KiB ").unwrap(), 64 << 10);
+        assert_eq!(parse_size("1.5G").unwrap(), 3 << 29);
+        assert_eq!(parse_size("100b").unwrap(), 100);
+        for bad in ["", "MB", "12TB", "-1G", "1.2.3M", "1 G B"] {
+            assert!(parse_size(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn cpus() {
+        assert_eq!(parse_cpu("1.0").unwrap(), 1.0);
+        assert_eq!(parse_cpu("2").unwrap(), 2.0);
+        assert_eq!(parse_cpu("50%").unwrap(), 0.5);
+        assert_eq!(parse_cpu(" 150 % ").unwrap(), 1.5);
+        for bad in ["", "0", "0%", "-1", "abc", "%", "inf", "NaN"] {
+            assert!(parse_cpu(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
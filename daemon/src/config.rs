@@ -0,0 +1,478 @@
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::install::InstallArgs;
+
+// Precedence: command-line flag > env var > flared.toml > default
+
+#[derive(Parser, Debug)]
+#[command(name = "flared", version, about = "Flare daemon")]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(long, env = "FLARE_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    // address for every listener unless set per listener in flared.toml
+    #[arg(long, env = "FLARE_BIND")]
+    pub bind: Option<String>,
+    #[arg(long, env = "FLARE_PORT")]
+    pub port: Option<u16>,
+    #[arg(long, env = "FLARE_GATEWAY_PORT")]
+    pub gateway_port: Option<u16>,
+    #[arg(long, env = "FLARE_HEALTH_PORT")]
+    pub health_port: Option<u16>,
+    #[arg(long, env = "FLARE_DISCOVERY_PORT")]
+    pub discovery_port: Option<u16>,
+
+    #[arg(long, env = "FLARE_DATA_DIR", global = true)]
+    pub data_dir: Option<PathBuf>,
+    #[arg(long, env = "FLARE_APPS_DIR")]
+    pub apps_dir: Option<PathBuf>,
+    #[arg(long, env = "FLARE_SOCKET")]
+    pub socket: Option<PathBuf>,
+    #[arg(long, env = "FLARE_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, env = "FLARE_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    // "text" or "json"
+    #[arg(long, env = "FLARE_LOG_FORMAT")]
+    pub log_format: Option<String>,
+
+    #[arg(long)]
+    pub no_gateway: bool,
+    #[arg(long)]
+    pub no_health: bool,
+    #[arg(long)]
+    pub no_discovery: bool,
+    #[arg(long)]
+    pub no_local: bool,
+    #[arg(long, env = "FLARE_AUTO_APPROVE", value_parser = clap::builder::BoolishValueParser::new())]
+    pub auto_approve: Option<bool>,
+    // "keep" or "stop"
+    #[arg(long, env = "FLARE_SHUTDOWN_APPS")]
+    pub shutdown_apps: Option<String>,
+
+    // set by the previous binary when it re-execs us after an upgrade
+    #[arg(long, hide = true)]
+    pub inherit_fds: Option<String>,
+    #[arg(long, hide = true)]
+    pub upgrade_backup: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    // set flared up as a service: unit/init script, data dir, identity certificate
+    Install(InstallArgs),
+    Uninstall {
+        #[command(flatten)]
+        target: InstallArgs,
+        // also delete the data directory (apps, tokens, certificate)
+        #[arg(long)]
+        purge: bool,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // daemon tokens, admin socket
+    pub data_dir: PathBuf,
+    // default: <data_dir>/apps
+    pub apps_dir: Option<PathBuf>,
+    pub control: Listener,
+    pub gateway: Listener,
+    pub health: Listener,
+    pub discovery: Listener,
+    pub local: LocalSection,
+    pub tls: TlsSection,
+    pub log: LogSection,
+    pub pairing: PairingSection,
+    pub shutdown: ShutdownSection,
+    pub upgrade: UpgradeSection,
+    pub cgroups: CgroupsSection,
+    pub smtp: SmtpSection,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    pub enabled: bool,
+    pub bind: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalSection {
+    pub enabled: bool,
+    // default: <data_dir>/flared.sock
+    pub socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    // self-signed when not set
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    pub format: String, // "text", "json"
+    // tracing filter, RUST_LOG wins when set
+    pub filter: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PairingSection {
+    // accept tokens registered over the network without local approval
+    pub auto_approve: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownSection {
+    // "keep": apps outlive the daemon and get re-adopted, "stop": SIGTERM them
+    pub apps: String,
+    // seconds in-flight deploys and stopping apps get before they are cut off
+    pub timeout: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpgradeSection {
+    // hex ed25519 public key, when set only binaries signed with it are accepted
+    pub public_key: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CgroupsSection {
+    // apps with [resource_limits] get a cgroup v2 below this
+    pub root: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpSection {
+    // mail server for mailto: notifications
+    pub host: Option<String>,
+    // default: 587 for starttls, 465 for tls, 25 for none
+    pub port: Option<u16>,
+    // "starttls", "tls" or "none"
+    pub tls: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // default: flare@<hostname>
+    pub from: Option<String>,
+}
+
+impl Listener {
+    fn new(port: u16) -> Self {
+        Self {
+            enabled: true,
+            bind: "0.0.0.0".into(),
+            port,
+        }
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.bind, self.port)
+    }
+}
+
+impl Default for LocalSection {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket: None,
+        }
+    }
+}
+
+impl Default for LogSection {
+    fn default() -> Self {
+        Self {
+            format: "text".into(),
+            filter: "info".into(),
+        }
+    }
+}
+
+impl Default for ShutdownSection {
+    fn default() -> Self {
+        Self {
+            apps: "keep".into(),
+            timeout: 30,
+        }
+    }
+}
+
+impl Default for CgroupsSection {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("/sys/fs/cgroup/flare"),
+        }
+    }
+}
+
+impl Default for SmtpSection {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: None,
+            tls: "starttls".into(),
+            username: None,
+            password: None,
+            from: None,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: common::flare_dir(),
+            apps_dir: None,
+            control: Listener::new(7530),
+            gateway: Listener::new(80),
+            health: Listener::new(7531),
+            discovery: Listener::new(7001),
+            local: LocalSection::default(),
+            tls: TlsSection::default(),
+            log: LogSection::default(),
+            pairing: PairingSection::default(),
+            shutdown: ShutdownSection::default(),
+            upgrade: UpgradeSection::default(),
+            cgroups: CgroupsSection::default(),
+            smtp: SmtpSection::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn apps_dir(&self) -> PathBuf {
+        self.apps_dir
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join("apps"))
+    }
+
+    // [storage] type = "local" volumes, outside the releases
+    pub fn volumes_dir(&self) -> PathBuf {
+        self.data_dir.join("volumes")
+    }
+
+    pub fn app_dir(&self, name: &str) -> PathBuf {
+        self.apps_dir().join(name.replace("/", "_"))
+    }
+
+    pub fn socket_path(&self) -> PathBuf {
+        self.local
+            .socket
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join("flared.sock"))
+    }
+
+    // health endpoint as seen from this host
+    pub fn health_url(&self) -> String {
+        format!("http://localhost:{}", self.health.port)
+    }
+
+    pub fn shutdown_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.shutdown.timeout)
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config not loaded")
+}
+
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+// --config, then ~/.flare/flared.toml, then /etc/flare/flared.toml
+fn config_file(args: &Args) -> Option<PathBuf> {
+    if let Some(path) = &args.config {
+        return Some(path.clone());
+    }
+
+    [
+        common::flare_dir().join("flared.toml"),
+        PathBuf::from("/etc/flare/flared.toml"),
+    ]
+    .into_iter()
+    .find(|p| p.exists())
+}
+
+pub fn load(args: &Args) -> Result<Config> {
+    let mut config = match config_file(args) {
+        Some(path) => {
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Can't read {:?}: {}", path, e))?;
+            let file: toml::Value = toml::from_str(&content)
+                .map_err(|e| anyhow::anyhow!("Bad config {:?}: {}", path, e))?;
+
+            // file only needs the keys it changes
+            let mut merged = toml::Value::try_from(Config::default())?;
+            merge(&mut merged, file);
+            merged
+                .try_into()
+                .map_err(|e| anyhow::anyhow!("Bad config {:?}: {}", path, e))?
+        }
+        None => Config::default(),
+    };
+
+    if let Some(bind) = &args.bind {
+        for l in [
+            &mut config.control,
+            &mut config.gateway,
+            &mut config.health,
+            &mut config.discovery,
+        ] {
+            l.bind = bind.clone();
+        }
+    }
+
+    if let Some(p) = args.port {
+        config.control.port = p;
+    }
+    if let Some(p) = args.gateway_port {
+        config.gateway.port = p;
+    }
+    if let Some(p) = args.health_port {
+        config.health.port = p;
+    }
+    if let Some(p) = args.discovery_port {
+        config.discovery.port = p;
+    }
+
+    if let Some(dir) = &args.data_dir {
+        config.data_dir = dir.clone();
+    }
+    if let Some(dir) = &args.apps_dir {
+        config.apps_dir = Some(dir.clone());
+    }
+    if let Some(path) = &args.socket {
+        config.local.socket = Some(path.clone());
+    }
+    if let Some(path) = &args.tls_cert {
+        config.tls.cert = Some(path.clone());
+    }
+    if let Some(path) = &args.tls_key {
+        config.tls.key = Some(path.clone());
+    }
+    if let Some(format) = &args.log_format {
+        config.log.format = format.clone();
+    }
+    if let Some(auto) = args.auto_approve {
+        config.pairing.auto_approve = auto;
+    }
+    if let Some(apps) = &args.shutdown_apps {
+        config.shutdown.apps = apps.clone();
+    }
+
+    config.gateway.enabled &= !args.no_gateway;
+    config.health.enabled &= !args.no_health;
+    config.discovery.enabled &= !args.no_discovery;
+    config.local.enabled &= !args.no_local;
+
+    if !matches!(config.log.format.as_str(), "text" | "json") {
+        anyhow::bail!("Unknown log format: {}", config.log.format);
+    }
+    if !matches!(config.smtp.tls.as_str(), "starttls" | "tls" | "none") {
+        anyhow::bail!("Unknown smtp.tls: {}", config.smtp.tls);
+    }
+    if !matches!(config.shutdown.apps.as_str(), "keep" | "stop") {
+        anyhow::bail!("Unknown shutdown.apps: {}", config.shutdown.apps);
+    }
+
+    Ok(config)
+}
+
+fn merge(base: &mut toml::Value, over: toml::Value) {
+    match (base, over) {
+        (toml::Value::Table(base), toml::Value::Table(over)) => {
+            for (k, v) in over {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_with(file: &str, flags: &[&str]) -> Result<Config> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("flared.toml");
+        std::fs::write(&path, file)?;
+        let config = path.to_string_lossy().into_owned();
+        let args = ["flared", "--config", &config];
+        load(&Args::try_parse_from(args.iter().chain(flags))?)
+    }
+
+    #[test]
+    fn flags_win_over_the_file() {
+        let file = r#"
+            [control]
+            port = 9000
+            [gateway]
+            port = 8080
+            bind = "127.0.0.1"
+            [shutdown]
+            apps = "stop"
+        "#;
+        let config = load_with(file, &["--port", "9100", "--no-gateway"]).unwrap();
+        assert_eq!(config.control.port, 9100);
+        assert_eq!(config.gateway.port, 8080);
+        assert!(!config.gateway.enabled);
+        assert_eq!(config.shutdown.apps, "stop");
+
+        let config = load_with(file, &["--bind", "10.0.0.1"]).unwrap();
+        assert_eq!(config.gateway.bind, "10.0.0.1");
+        assert_eq!(config.control.port, 9000);
+    }
+
+    #[test]
+    fn file_only_changes_its_keys() {
+        let config = load_with("[gateway]\nbind = \"127.0.0.1\"\n", &[]).unwrap();
+        assert_eq!(config.gateway.bind, "127.0.0.1");
+        assert_eq!(config.gateway.port, 80);
+        assert!(config.gateway.enabled);
+        assert_eq!(config.health.port, 7531);
+        assert_eq!(config.shutdown.timeout, 30);
+    }
+
+    #[test]
+    fn flags_cant_turn_a_listener_back_on() {
+        let config = load_with("[health]\nenabled = false\n", &["--health-port", "9200"]).unwrap();
+        assert!(!config.health.enabled);
+        assert_eq!(config.health.port, 9200);
+    }
+
+    #[test]
+    fn refuses_unknown_keys_and_values() {
+        assert!(load_with("[control]\nprot = 1\n", &[]).is_err());
+        assert!(load_with("[log]\nformat = \"xml\"\n", &[]).is_err());
+        assert!(load_with("", &["--shutdown-apps", "pause"]).is_err());
+    }
+}
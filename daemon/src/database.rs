@@ -1,19 +1,32 @@
 use anyhow::Result;
 use common::DatabaseSection;
-use std::path::PathBuf;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::Duration;
 use tracing::info;
 
-pub fn setup(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
+use crate::identity::Identity;
+use crate::storage::Volume;
+
+// `timeout` bounds preseeding, an sqlite database goes into `volume` and
+// belongs to `identity`
+pub fn setup(
+    db: &DatabaseSection,
+    dir: &PathBuf,
+    timeout: Option<Duration>,
+    volume: Option<&Volume>,
+    identity: Option<&Identity>,
+) -> Result<()> {
     match db.r#type.as_str() {
-        "postgres" => postgres(db, dir),
-        "mysql" => mysql(db, dir),
-        "sqlite" => sqlite(db, dir),
+        "postgres" => postgres(db, dir, timeout),
+        "mysql" => mysql(db, dir, timeout),
+        "sqlite" => sqlite(db, dir, timeout, volume, identity),
         t => anyhow::bail!("Unknown database: {}", t),
     }
 }
 
-fn postgres(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
+fn postgres(db: &DatabaseSection, dir: &PathBuf, timeout: Option<Duration>) -> Result<()> {
     let name = db.name.as_deref().unwrap_or("postgres");
     let user = db.user.as_deref().unwrap_or("postgres");
     let pass = db.password.as_deref().unwrap_or("password");
@@ -55,13 +68,19 @@ fn postgres(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
     }
 
     std::thread::sleep(std::time::Duration::from_secs(5));
-    run_preseed(&container, db, dir, &["psql", "-U", user, "-d", name])?;
+    run_preseed(
+        &container,
+        db,
+        dir,
+        &["psql", "-U", user, "-d", name],
+        timeout,
+    )?;
 
     info!("PostgreSQL ready on port {}", actual_port);
     Ok(())
 }
 
-fn mysql(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
+fn mysql(db: &DatabaseSection, dir: &PathBuf, timeout: Option<Duration>) -> Result<()> {
     let name = db.name.as_deref().unwrap_or("mysql");
     let user = db.user.as_deref().unwrap_or("root");
     let pass = db.password.as_deref().unwrap_or("password");
@@ -100,27 +119,64 @@ fn mysql(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
         db,
         dir,
         &["mysql", "-u", user, &format!("-p{}", pass), name],
+        timeout,
     )?;
 
     info!("MySQL ready on port {}", port);
     Ok(())
 }
 
-fn sqlite(db: &DatabaseSection, dir: &PathBuf) -> Result<()> {
+fn sqlite(
+    db: &DatabaseSection,
+    dir: &PathBuf,
+    timeout: Option<Duration>,
+    volume: Option<&Volume>,
+    identity: Option<&Identity>,
+) -> Result<()> {
     let name = db.name.as_deref().unwrap_or("app.db");
-    let path = dir.join(name);
+    // with a volume the release only has a symlink, the data survives it
+    let path = match volume {
+        Some(v) => v.keep(dir, name)?,
+        None => common::path_inside(dir, Path::new(name), false)?,
+    };
 
-    if !path.exists() {
-        std::fs::File::create(&path)?;
+    // a persistent database is seeded once
+    let fresh = !path.exists();
+    if fresh {
+        // not through a link the release may have put there
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        if let Some(id) = identity {
+            id.own(&path)?;
+        }
     }
 
-    if let Some(preseed) = &db.preseed {
-        let sql_path = dir.join(preseed);
+    // as the app, so the journal is its own too and a database the release
+    // links elsewhere isn't written with our rights
+    if let Some(preseed) = db.preseed.as_ref().filter(|_| fresh || volume.is_none()) {
+        // we open it, not a link to something the app may not read
+        let sql_path = common::path_inside(dir, Path::new(preseed), false)?;
         if sql_path.exists() {
-            Command::new("sqlite3")
-                .arg(&path)
-                .stdin(std::fs::File::open(&sql_path)?)
-                .status()?;
+            let sql = std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&sql_path)?;
+            let mut sqlite3 = Command::new("sqlite3");
+            sqlite3.arg(&path).stdin(sql);
+            if let Some(id) = identity {
+                id.apply(&mut sqlite3);
+            }
+            let seeded = crate::oneshot::run(&mut sqlite3, "Preseed", timeout);
+            if !seeded.as_ref().is_ok_and(|s| s.success()) {
+                // an empty database would never be seeded again
+                if fresh {
+                    let _ = std::fs::remove_file(&path);
+                }
+                seeded?;
+                anyhow::bail!("Preseed failed");
+            }
         }
     }
 
@@ -133,7 +189,13 @@ fn stop_container(name: &str) {
     let _ = Command::new("docker").args(["rm", name]).status();
 }
 
-fn run_preseed(container: &str, db: &DatabaseSection, dir: &PathBuf, cmd: &[&str]) -> Result<()> {
+fn run_preseed(
+    container: &str,
+    db: &DatabaseSection,
+    dir: &PathBuf,
+    cmd: &[&str],
+    timeout: Option<Duration>,
+) -> Result<()> {
     let preseed = match &db.preseed {
         Some(p) => p,
         None => return Ok(()),
@@ -147,10 +209,9 @@ fn run_preseed(container: &str, db: &DatabaseSection, dir: &PathBuf, cmd: &[&str
     let mut args = vec!["exec", "-i", container];
     args.extend(cmd);
 
-    Command::new("docker")
-        .args(&args)
-        .stdin(std::fs::File::open(&sql_path)?)
-        .status()?;
+    let mut docker = Command::new("docker");
+    docker.args(&args).stdin(std::fs::File::open(&sql_path)?);
+    crate::oneshot::run(&mut docker, "Preseed", timeout)?;
 
     Ok(())
 }
@@ -1,39 +1,89 @@
 use anyhow::Result;
 use common::{AppConfig, AppState, DeployRequest};
-use common::{app_dir, load_app_config, save_state};
+use common::{load_app_config, save_state};
 use flate2::read::GzDecoder;
 use std::collections::HashMap;
 use std::env;
-use std::io::Cursor;
-use std::path::PathBuf;
+use std::io::{Cursor, Read};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::Duration;
 use tar::Archive;
 use tracing::{info, warn};
 
+use crate::cgroup::Limits;
 use crate::env_loader::prepare_env;
+use crate::hooks::Context;
+use crate::identity::Identity;
 use crate::server::HealthPids;
 use crate::server::Routes;
-
-pub async fn run(req: &DeployRequest, routes: Routes, health_pids: HealthPids) -> Result<PathBuf> {
+use crate::shutdown::Shutdown;
+
+pub async fn run(
+    req: &DeployRequest,
+    routes: Routes,
+    health_pids: HealthPids,
+    shutdown: &Shutdown,
+) -> Result<PathBuf> {
     let archive = download(req).await?;
-    let dir = extract(&req.repo, &archive)?;
-    let config = load_app_config(&dir)?;
-
-    crate::hooks::run_pre(&config, &dir);
-
-    if let Some(build) = &config.build {
-        build_app(&build.command, &dir)?;
-    }
+    shutdown.check()?;
+    let commit = commit_sha(&archive);
+    let (dir, backup) = extract(&req.repo, &archive)?;
+    let previous = backup.as_deref();
+    // the running release's, until the new one is ready
+    let pidfile = crate::adopt::saved(&dir);
+    let put_back = || {
+        if let Some(backup) = &backup
+            && let Err(e) = restore(&dir, backup)
+        {
+            warn!("Can't restore {:?}: {}", backup, e);
+        }
+    };
 
-    if let Some(db) = &config.database {
-        crate::database::setup(db, &dir)?;
-    }
+    // build and db setup block, keep the runtime (and signal handling) going
+    let prepared =
+        tokio::task::block_in_place(|| prepare(&dir, shutdown, previous, commit.as_deref()));
+    let (config, identity) = match prepared {
+        Ok(r) => r,
+        Err(e) => {
+            // failed or aborted by shutdown: the previous release is still
+            // running, its files go back under it
+            put_back();
+            return Err(e);
+        }
+    };
 
-    let pid = start(&config, &dir, routes.clone()).await?;
+    let hooks = Context {
+        config: &config,
+        dir: &dir,
+        identity: identity.as_ref(),
+        previous,
+        commit: commit.as_deref(),
+    };
+    // the new release gets the port (and the pidfile) to itself
+    let stopped = stop_previous(&dir).await?;
+    let pid = match start(&hooks, routes.clone()).await {
+        Ok(pid) => pid,
+        Err(e) => {
+            // never got ready: back to the previous release, running again if it was
+            put_back();
+            if stopped {
+                let app = dir.file_name().unwrap_or_default().to_string_lossy();
+                if let Err(e) = tokio::task::block_in_place(|| crate::server::start_app(&app)) {
+                    warn!("Can't start the previous release again: {}", e);
+                }
+            } else {
+                crate::adopt::put_back(&dir, pidfile);
+            }
+            return Err(e);
+        }
+    };
 
     // Register PID for health check
     let app_name = &config.app.name;
-    crate::health_server::update_pid(&health_pids, app_name, pid);
+    crate::health_server::update_pid(&health_pids, app_name, pid).await;
 
     let state = AppState {
         name: config.app.name.clone(),
@@ -43,21 +93,78 @@ pub async fn run(req: &DeployRequest, routes: Routes, health_pids: HealthPids) -
         port: config.run.as_ref().and_then(|r| r.port),
         health_url: determine_health_url(&config.health, app_name),
         isolation: config.isolation.as_ref().map(|i| i.r#type.clone()),
+        oom_kills: 0,
+        commit: commit.clone(),
+        // it got here by passing its readiness check
+        health: pid.and(config.health.as_ref()).map(|_| "healthy".into()),
+        deployed_at: Some(chrono::Utc::now().timestamp()),
     };
     save_state(&dir, &state)?;
 
-    // Spawn health check only if url is set
-    if let Some(url) = &config.health {
-        if let Some(health_url) = determine_health_url(&config.health, app_name) {
-            spawn_health_check(&health_url, app_name);
-        }
+    if pid.is_some() {
+        crate::health::watch(&dir, &config, true);
     }
 
-    crate::hooks::run_post(&config, &dir);
+    let hooks = Context {
+        config: &config,
+        dir: &dir,
+        identity: identity.as_ref(),
+        previous,
+        commit: commit.as_deref(),
+    };
+    // the release is live already, failing the deploy now wouldn't undo it
+    if let Err(e) = tokio::task::block_in_place(|| crate::hooks::run(&hooks, "post_deploy")) {
+        warn!("{}", e);
+    }
 
     Ok(dir)
 }
 
+// everything between extract and start, the steps a shutdown may abort
+fn prepare(
+    dir: &PathBuf,
+    shutdown: &Shutdown,
+    previous: Option<&Path>,
+    commit: Option<&str>,
+) -> Result<(AppConfig, Option<Identity>)> {
+    let config = load_app_config(dir)?;
+
+    let identity = crate::identity::for_app(&config, dir)?;
+    if let Some(id) = &identity {
+        id.own_release(dir)?;
+    }
+    let volume = crate::storage::Volume::of(&config, dir)?;
+    if let Some(v) = &volume {
+        v.attach(identity.as_ref())?;
+    }
+
+    let hooks = Context {
+        config: &config,
+        dir,
+        identity: identity.as_ref(),
+        previous,
+        commit,
+    };
+    crate::hooks::run(&hooks, "pre_deploy")?;
+
+    if let Some(build) = &config.build {
+        shutdown.check()?;
+        crate::hooks::run(&hooks, "pre_build")?;
+        let timeout = crate::oneshot::timeout(build.timeout.as_deref(), &config)?;
+        build_app(&build.command, dir, identity.as_ref(), timeout)?;
+        crate::hooks::run(&hooks, "post_build")?;
+    }
+
+    if let Some(db) = &config.database {
+        shutdown.check()?;
+        let timeout = crate::oneshot::timeout(None, &config)?;
+        crate::database::setup(db, dir, timeout, volume.as_ref(), identity.as_ref())?;
+    }
+
+    shutdown.check()?;
+    Ok((config, identity))
+}
+
 async fn download(req: &DeployRequest) -> Result<Vec<u8>> {
     let url = if req.forge == "github" {
         format!("https://api.github.com/repos/{}/tarball/main", req.repo)
@@ -86,41 +193,202 @@ async fn download(req: &DeployRequest) -> Result<Vec<u8>> {
     Ok(resp.bytes().await?.to_vec())
 }
 
-fn extract(repo: &str, data: &[u8]) -> Result<PathBuf> {
-    let dir = app_dir(repo);
+// previous releases kept in versions/, for rollbacks
+const VERSIONS_KEPT: usize = 5;
+
+// git archive, and so GitHub/Gitea tarballs, keep the commit id in a pax global header
+fn commit_sha(data: &[u8]) -> Option<String> {
+    let mut archive = Archive::new(GzDecoder::new(Cursor::new(data)));
+    let mut first = archive.entries().ok()?.next()?.ok()?;
+    if first.header().entry_type() != tar::EntryType::XGlobalHeader {
+        return None;
+    }
+    let mut records = String::new();
+    first.read_to_string(&mut records).ok()?;
+    // "52 comment=<sha>"
+    records
+        .lines()
+        .find_map(|l| l.split_once(" comment="))
+        .map(|(_, sha)| sha.to_string())
+}
+
+// unpacks over the release in place, a copy of which goes to versions/ first
+fn extract(repo: &str, data: &[u8]) -> Result<(PathBuf, Option<PathBuf>)> {
+    let dir = crate::config::get().app_dir(repo);
     std::fs::create_dir_all(&dir)?;
 
-    backup_current(&dir)?;
+    // the archive may ship files below the volume's mount, prepare links it again
+    let volume = crate::storage::Volume::detach(&dir);
+    let reattach = || {
+        if let Some(v) = &volume
+            && let Err(e) = v.attach(None)
+        {
+            warn!("{}", e);
+        }
+    };
 
+    let backup = match backup_current(&dir) {
+        Ok(b) => b,
+        Err(e) => {
+            reattach();
+            return Err(e);
+        }
+    };
     let gz = GzDecoder::new(Cursor::new(data));
-    Archive::new(gz).unpack(&dir)?;
+    if let Err(e) = Archive::new(gz).unpack(&dir) {
+        match &backup {
+            Some(b) => {
+                if let Err(e) = restore(&dir, b) {
+                    warn!("Can't restore {:?}: {}", b, e);
+                }
+            }
+            None => reattach(),
+        }
+        return Err(e.into());
+    }
 
     info!("Extracted to {:?}", dir);
-    Ok(dir)
+    Ok((dir, backup))
 }
 
-fn backup_current(dir: &PathBuf) -> Result<()> {
-    let current = dir.join("current");
-    if !current.exists() {
-        return Ok(());
+// what in the app dir belongs to the release, not to the app's bookkeeping
+fn release_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let ours = matches!(name.as_str(), "versions" | "state.toml" | "flare.pid")
+            || name.starts_with(".state.toml.")
+            || name.starts_with(".flare.pid.");
+        if !ours {
+            entries.push(entry.path());
+        }
     }
+    Ok(entries)
+}
 
-    let ts = chrono::Utc::now().timestamp();
-    let backup = dir.join("versions").join(ts.to_string());
-    std::fs::create_dir_all(backup.parent().unwrap())?;
+// Copies the release in `dir` to versions/<timestamp>, None before the
+// first deploy. Only the newest VERSIONS_KEPT copies stay.
+fn backup_current(dir: &Path) -> Result<Option<PathBuf>> {
+    if !dir.join("flare.toml").exists() {
+        return Ok(None);
+    }
 
-    if let Ok(target) = std::fs::read_link(&current) {
-        std::fs::rename(target, &backup)?;
+    let versions = dir.join("versions");
+    if versions.symlink_metadata().is_ok() {
+        crate::identity::keep_private(&versions)?;
+    }
+    let mut ts = chrono::Utc::now().timestamp();
+    while versions.join(ts.to_string()).exists() {
+        ts += 1;
+    }
+    let backup = versions.join(ts.to_string());
+    std::fs::create_dir_all(&backup)?;
+    crate::identity::keep_private(&versions)?;
+
+    // owners, modes and symlinks as they are
+    let entries = release_entries(dir)?;
+    if !entries.is_empty() {
+        let status = Command::new("cp")
+            .arg("-a")
+            .args(&entries)
+            .arg(&backup)
+            .status()?;
+        if !status.success() {
+            let _ = std::fs::remove_dir_all(&backup);
+            anyhow::bail!("Can't copy the current release to {:?}", backup);
+        }
+    }
+    info!("Previous release saved to {:?}", backup);
+
+    let mut kept: Vec<PathBuf> = std::fs::read_dir(&versions)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    kept.sort();
+    for old in kept.iter().take(kept.len().saturating_sub(VERSIONS_KEPT)) {
+        let _ = std::fs::remove_dir_all(old);
+    }
+    Ok(Some(backup))
+}
+
+// A backup's flare.toml says who the app runs as, only one we wrote is
+// trusted.
+pub fn check_backup(backup: &Path) -> Result<()> {
+    let ours = unsafe { libc::geteuid() };
+    for path in [backup.to_path_buf(), backup.join("flare.toml")] {
+        let meta = std::fs::symlink_metadata(&path)?;
+        if meta.file_type().is_symlink() || meta.uid() != ours {
+            anyhow::bail!("{:?} isn't flared's, not restoring it", path);
+        }
     }
     Ok(())
 }
 
-fn build_app(cmd: &str, dir: &PathBuf) -> Result<()> {
+// Puts the release saved in `backup` back in place of the one in `dir`,
+// owned and with its volume linked like a deploy would.
+pub fn restore(dir: &Path, backup: &Path) -> Result<()> {
+    check_backup(backup)?;
+    info!("Restoring {:?}", backup);
+    crate::storage::Volume::detach(dir);
+    for entry in release_entries(dir)? {
+        if std::fs::symlink_metadata(&entry)?.is_dir() {
+            std::fs::remove_dir_all(&entry)?;
+        } else {
+            std::fs::remove_file(&entry)?;
+        }
+    }
+    for entry in std::fs::read_dir(backup)? {
+        let entry = entry?;
+        std::fs::rename(entry.path(), dir.join(entry.file_name()))?;
+    }
+    std::fs::remove_dir(backup)?;
+
+    let config = load_app_config(&dir.to_path_buf())?;
+    let identity = crate::identity::for_app(&config, dir)?;
+    if let Some(id) = &identity {
+        id.own_release(dir)?;
+    }
+    if let Some(v) = crate::storage::Volume::of(&config, dir)? {
+        v.attach(identity.as_ref())?;
+    }
+    Ok(())
+}
+
+// Stops the running release before the new one starts, so the port is free
+// and readiness probes can only reach the new one. true if one was running.
+async fn stop_previous(dir: &PathBuf) -> Result<bool> {
+    let Some(mut state) = common::load_state(dir)? else {
+        return Ok(false);
+    };
+    let Some(pid) = state.pid.filter(|_| state.status == "running") else {
+        return Ok(false);
+    };
+
+    info!("Stopping the previous release (PID {})", pid);
+    crate::health::unwatch(dir);
+    crate::process::stop_wait(pid, crate::config::get().shutdown_timeout()).await;
+    crate::adopt::forget(dir);
+    state.status = "stopped".into();
+    state.pid = None;
+    state.health = None;
+    save_state(dir, &state)?;
+    Ok(true)
+}
+
+fn build_app(
+    cmd: &str,
+    dir: &PathBuf,
+    identity: Option<&Identity>,
+    timeout: Option<Duration>,
+) -> Result<()> {
     info!("Building: {}", cmd);
-    let status = Command::new("sh")
-        .args(["-c", cmd])
-        .current_dir(dir)
-        .status()?;
+    let mut build = Command::new("sh");
+    build.args(["-c", cmd]).current_dir(dir);
+    if let Some(id) = identity {
+        id.apply(&mut build);
+    }
+    let status = crate::oneshot::run(&mut build, "Build", timeout)?;
 
     if !status.success() {
         anyhow::bail!("Build failed");
@@ -128,7 +396,11 @@ fn build_app(cmd: &str, dir: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-async fn start(config: &AppConfig, dir: &PathBuf, routes: Routes) -> Result<Option<u32>> {
+async fn start(hooks: &Context<'_>, routes: Routes) -> Result<Option<u32>> {
+    let (config, dir) = (hooks.config, hooks.dir);
+    if let Some(bucket) = crate::s3::Bucket::of(config, dir)? {
+        bucket.provision().await?;
+    }
     if let Some(web) = &config.web {
         let root = dir.join(web.root.as_deref().unwrap_or("."));
         routes
@@ -145,22 +417,74 @@ async fn start(config: &AppConfig, dir: &PathBuf, routes: Routes) -> Result<Opti
         None => return Ok(None),
     };
 
-    let mut cmd = build_run_command(run, config, dir);
-    let child = cmd.spawn()?;
-    let pid = child.id();
-
+    tokio::task::block_in_place(|| crate::hooks::run(hooks, "pre_start"))?;
+    let pid = spawn(run, config, dir)?;
+    let ready = async {
+        crate::health::wait_ready(config, dir, pid).await?;
+        tokio::task::block_in_place(|| crate::hooks::run(hooks, "post_start"))
+    };
+    if let Err(e) = ready.await {
+        // not tracked by any state yet, and the previous release may want its port back
+        if crate::process::alive(pid) {
+            crate::process::stop_wait(pid, crate::config::get().shutdown_timeout()).await;
+        }
+        return Err(e);
+    }
     info!("Started PID {}", pid);
     Ok(Some(pid))
 }
 
-fn build_run_command(run: &common::RunSection, config: &AppConfig, dir: &PathBuf) -> Command {
-    let final_vars = prepare_env(dir, config.env.as_ref());
+// [run] command with the app's env, isolation and resource limits
+pub fn spawn(run: &common::RunSection, config: &AppConfig, dir: &PathBuf) -> Result<u32> {
+    let limits = Limits::parse(config.resource_limits.as_ref())?;
+    let identity = crate::identity::for_app(config, dir)?;
+    let mut cmd = build_run_command(run, config, dir, &limits, identity.as_ref())?;
+
+    let systemd = config
+        .isolation
+        .as_ref()
+        .is_some_and(|i| i.r#type == "systemd");
+    if !limits.is_empty() && !systemd {
+        crate::cgroup::apply(&mut cmd, dir, &limits)?;
+    }
+    crate::landlock::apply(&mut cmd, config, dir)?;
+
+    // own process group, see crate::process
+    cmd.process_group(0);
+    let child = cmd.spawn()?;
+    let pid = child.id();
+    crate::adopt::record(dir, pid, &cmd);
+    Ok(pid)
+}
+
+fn build_run_command(
+    run: &common::RunSection,
+    config: &AppConfig,
+    dir: &PathBuf,
+    limits: &Limits,
+    identity: Option<&Identity>,
+) -> Result<Command> {
+    let mut final_vars = prepare_env(dir, config.env.as_ref());
+    if let Some(id) = identity {
+        id.env(&mut final_vars);
+    }
+    if let Some(bucket) = crate::s3::Bucket::of(config, dir)? {
+        bucket.env(&mut final_vars);
+    }
     let isolation = config.isolation.as_ref().map(|i| i.r#type.as_str());
 
     let mut cmd = match isolation {
         Some("systemd") => {
             let mut c = Command::new("systemd-run");
-            c.args(["--user", "--scope"]);
+            match identity {
+                // system manager does the switch, a user manager can't
+                Some(id) => c.args([format!("--uid={}", id.uid), format!("--gid={}", id.gid)]),
+                None => c.arg("--user"),
+            };
+            c.arg("--scope");
+            for prop in limits.systemd_properties() {
+                c.arg("-p").arg(prop);
+            }
 
             // env
             for (k, v) in final_vars {
@@ -170,49 +494,31 @@ fn build_run_command(run: &common::RunSection, config: &AppConfig, dir: &PathBuf
             c.args(["sh", "-c", &run.command]);
             c
         }
-        Some("chroot") => {
-            let mut c = Command::new("chroot");
-            c.arg(dir).args(["sh", "-c", &run.command]);
+        Some("sandbox") | Some("chroot") => {
+            if isolation == Some("chroot") {
+                warn!("isolation \"chroot\" is now \"sandbox\", please rename it");
+            }
+            let mut c = crate::sandbox::command(config, dir)?;
+            c.args(["sh", "-c", &run.command]);
             c.envs(final_vars);
+            if let Some(id) = identity {
+                id.apply(&mut c);
+            }
             c
         }
         _ => {
             let mut c = Command::new("sh");
             c.args(["-c", &run.command]);
             c.envs(final_vars); // Применяем переменные
+            if let Some(id) = identity {
+                id.apply(&mut c);
+            }
             c
         }
     };
 
     cmd.current_dir(dir);
-    cmd
-}
-
-fn spawn_health_check(url: &str, name: &str) {
-    let url = url.to_string();
-    let name = name.to_string();
-
-    tokio::spawn(async move {
-        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-
-        let result = reqwest::Client::new()
-            .get(&url)
-            .timeout(std::time::Duration::from_secs(10))
-            .send()
-            .await;
-
-        match result {
-            Ok(r) if r.status().is_success() => {
-                info!("Health OK: {}", name);
-            }
-            Ok(r) => {
-                tracing::warn!("Health failed {}: HTTP {}", name, r.status());
-            }
-            Err(e) => {
-                tracing::error!("Health failed {}: {}", name, e);
-            }
-        }
-    });
+    Ok(cmd)
 }
 
 fn determine_health_url(health: &Option<common::HealthSection>, app_name: &str) -> Option<String> {
@@ -220,8 +526,12 @@ fn determine_health_url(health: &Option<common::HealthSection>, app_name: &str)
     let health = health.as_ref()?;
 
     if health.auto_add {
-        Some(format!("http://localhost:7531/health/{}", app_name))
+        Some(format!(
+            "{}/health/{}",
+            crate::config::get().health_url(),
+            app_name
+        ))
     } else {
-        Some(health.url.clone())
+        health.url.clone()
     }
 }
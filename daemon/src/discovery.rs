@@ -2,9 +2,13 @@ use anyhow::Result;
 use tokio::net::UdpSocket;
 use tracing::info;
 
-pub async fn run(port: u16) -> Result<()> {
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).await?;
-    info!("Discovery listening on UDP {}", port);
+pub async fn run() -> Result<()> {
+    let config = crate::config::get();
+    let socket = UdpSocket::bind(config.discovery.addr()).await?;
+    info!("Discovery listening on UDP {}", config.discovery.addr());
+
+    // control port goes along so clients find daemons not on the default one
+    let reply = format!("FLARE_HERE {}", config.control.port);
 
     let mut buf = [0u8; 64];
 
@@ -14,7 +18,7 @@ pub async fn run(port: u16) -> Result<()> {
 
         if msg == "FLARE_DISCOVER" {
             info!("Discovery ping from {}", addr);
-            socket.send_to(b"FLARE_HERE", addr).await?;
+            socket.send_to(reply.as_bytes(), addr).await?;
         }
     }
 }
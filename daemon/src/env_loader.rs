@@ -15,6 +15,16 @@ pub fn prepare_env(
     resolve_vars(&conf_env, &dot_env)
 }
 
+// a config value that may be "$ENV:NAME", looked up like [env] does
+pub fn resolve(dir: &Path, value: &str) -> String {
+    match value.strip_prefix("$ENV:") {
+        Some(name) => load_dot_env_file(dir)
+            .remove(name)
+            .unwrap_or_else(|| env::var(name).unwrap_or_default()),
+        None => value.to_string(),
+    }
+}
+
 fn load_dot_env_file(dir: &Path) -> HashMap<String, String> {
     let mut vars = HashMap::new();
     let env_path = dir.join(".env");
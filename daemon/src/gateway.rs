@@ -6,6 +6,7 @@ use axum::{
     http::{Request, StatusCode},
     response::{IntoResponse, Response},
 };
+use std::time::Instant;
 use tokio::net::TcpListener;
 use tower::ServiceExt;
 use tower_http::services::ServeDir;
@@ -13,11 +14,10 @@ use tracing::info;
 
 use crate::server::Routes;
 
-pub async fn run(routes: Routes) -> Result<()> {
+pub async fn run(routes: Routes, listener: TcpListener) -> Result<()> {
     let app = Router::new().fallback(handler).with_state(routes);
 
-    let listener = TcpListener::bind("0.0.0.0:80").await?;
-    info!("Gateway on :80");
+    info!("Gateway on {}", listener.local_addr()?);
 
     axum::serve(listener, app).await?;
     Ok(())
@@ -25,6 +25,13 @@ pub async fn run(routes: Routes) -> Result<()> {
 
 async fn handler(State(routes): State<Routes>, Host(host): Host, req: Request<Body>) -> Response {
     let host = host.split(':').next().unwrap_or(&host).to_string();
+    let started = Instant::now();
+    let resp = route(&routes, &host, req).await;
+    crate::metrics::request(&host, resp.status().as_u16(), started.elapsed());
+    resp
+}
+
+async fn route(routes: &Routes, host: &str, req: Request<Body>) -> Response {
     let state = routes.read().await;
     let path = req.uri().path();
 
@@ -33,14 +40,14 @@ async fn handler(State(routes): State<Routes>, Host(host): Host, req: Request<Bo
         return proxy_to_health_server(path).await;
     }
 
-    if let Some(path) = state.static_sites.get(&host) {
+    if let Some(path) = state.static_sites.get(host) {
         return match ServeDir::new(path).oneshot(req).await {
             Ok(r) => r.into_response(),
             Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
         };
     }
 
-    if let Some(port) = state.proxy_routes.get(&host) {
+    if let Some(port) = state.proxy_routes.get(host) {
         return (StatusCode::OK, format!("proxy -> localhost:{}", port)).into_response();
     }
 
@@ -48,7 +55,7 @@ async fn handler(State(routes): State<Routes>, Host(host): Host, req: Request<Bo
 }
 
 async fn proxy_to_health_server(path: &str) -> Response {
-    let url = format!("http://localhost:7531{}", path);
+    let url = format!("{}{}", crate::config::get().health_url(), path);
 
     match reqwest::get(&url).await {
         Ok(resp) => {
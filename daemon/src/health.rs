@@ -0,0 +1,410 @@
+use anyhow::Result;
+use common::{AppConfig, load_app_config, load_state, save_state};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::identity::Identity;
+
+const DEFAULT_INTERVAL: u64 = 30;
+const DEFAULT_TIMEOUT: u64 = 10;
+const DEFAULT_THRESHOLD: u32 = 3;
+const DEFAULT_READY_TIMEOUT: u64 = 60;
+// between readiness probes
+const READY_POLL: Duration = Duration::from_secs(1);
+// checks kept per app
+const HISTORY_LEN: usize = 20;
+// this many restarts within the window make a crash loop
+const CRASH_RESTARTS: usize = 3;
+const CRASH_WINDOW: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub timestamp: i64,
+    pub ok: bool,
+    pub latency_ms: u64,
+    pub message: String,
+}
+
+// app dir name -> recent checks, oldest first
+static HISTORY: LazyLock<Mutex<HashMap<String, VecDeque<Check>>>> = LazyLock::new(Default::default);
+// app dir -> restarts by its watcher, for crash loop alerts
+static RESTARTS: LazyLock<Mutex<HashMap<PathBuf, Restarts>>> = LazyLock::new(Default::default);
+// app dir -> generation of its watcher, a newer watch() retires the old one
+static WATCHERS: LazyLock<Mutex<HashMap<PathBuf, u64>>> = LazyLock::new(Default::default);
+
+#[derive(Default)]
+struct Restarts {
+    // within CRASH_WINDOW
+    times: VecDeque<Instant>,
+    alerted: Option<Instant>,
+}
+
+pub enum Probe {
+    Http(String),
+    Tcp(u16),
+    // run as the app's user, like its hooks
+    Command(String, Option<Identity>),
+}
+
+// [health] with defaults filled in
+pub struct Policy {
+    pub probe: Probe,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub threshold: u32,
+    pub on_failure: String,
+    pub ready_timeout: Duration,
+}
+
+impl Policy {
+    // None without a [health] section
+    pub fn from_config(config: &AppConfig, dir: &Path) -> Result<Option<Self>> {
+        let Some(health) = &config.health else {
+            return Ok(None);
+        };
+
+        // type defaults to whatever is configured: command, url, else the app port
+        let kind = health
+            .r#type
+            .as_deref()
+            .unwrap_or(if health.command.is_some() {
+                "command"
+            } else if health.url.is_some() {
+                "http"
+            } else {
+                "tcp"
+            });
+        let probe = match kind {
+            "http" => Probe::Http(
+                health
+                    .url
+                    .clone()
+                    .ok_or_else(|| anyhow::anyhow!("health type http needs a url"))?,
+            ),
+            "tcp" => Probe::Tcp(
+                health
+                    .port
+                    .or(config.run.as_ref().and_then(|r| r.port))
+                    .ok_or_else(|| anyhow::anyhow!("health type tcp needs a port"))?,
+            ),
+            "command" => Probe::Command(
+                health
+                    .command
+                    .clone()
+                    .ok_or_else(|| anyhow::anyhow!("health type command needs a command"))?,
+                crate::identity::for_app(config, dir)?,
+            ),
+            other => anyhow::bail!("Unknown health type: {}", other),
+        };
+
+        let on_failure = health.on_failure.as_deref().unwrap_or("restart");
+        if !matches!(on_failure, "restart" | "degraded" | "rollback") {
+            anyhow::bail!("Unknown health on_failure: {}", on_failure);
+        }
+
+        Ok(Some(Self {
+            probe,
+            interval: Duration::from_secs(health.interval.unwrap_or(DEFAULT_INTERVAL).max(1)),
+            timeout: Duration::from_secs(health.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            threshold: health.failure_threshold.unwrap_or(DEFAULT_THRESHOLD).max(1),
+            on_failure: on_failure.to_string(),
+            ready_timeout: Duration::from_secs(
+                health.ready_timeout.unwrap_or(DEFAULT_READY_TIMEOUT),
+            ),
+        }))
+    }
+}
+
+// one probe, Err says what failed
+pub async fn probe(policy: &Policy, dir: &Path) -> Result<()> {
+    match &policy.probe {
+        Probe::Http(url) => {
+            let resp = reqwest::Client::new()
+                .get(url)
+                .timeout(policy.timeout)
+                .send()
+                .await?;
+            if !resp.status().is_success() {
+                anyhow::bail!("HTTP {}", resp.status());
+            }
+        }
+        Probe::Tcp(port) => {
+            tokio::time::timeout(
+                policy.timeout,
+                tokio::net::TcpStream::connect(("127.0.0.1", *port)),
+            )
+            .await
+            .map_err(|_| anyhow::anyhow!("connect to port {} timed out", port))??;
+        }
+        Probe::Command(cmd, identity) => {
+            let mut check = Command::new("sh");
+            check.args(["-c", cmd]).current_dir(dir);
+            if let Some(id) = identity {
+                id.apply(&mut check);
+            }
+            let timeout = policy.timeout;
+            let status = tokio::task::spawn_blocking(move || {
+                crate::oneshot::run(&mut check, "Health command", Some(timeout))
+            })
+            .await??;
+            if !status.success() {
+                anyhow::bail!("command failed ({})", status);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Probes a just started release until it passes, up to [health] ready_timeout.
+// Without a [health] section it's ready right away.
+pub async fn wait_ready(config: &AppConfig, dir: &Path, pid: u32) -> Result<()> {
+    let Some(policy) = Policy::from_config(config, dir)? else {
+        return Ok(());
+    };
+    let name = &config.app.name;
+    info!(
+        "Waiting up to {:?} for {} to get ready",
+        policy.ready_timeout, name
+    );
+
+    let deadline = Instant::now() + policy.ready_timeout;
+    loop {
+        if !crate::process::alive(pid) {
+            anyhow::bail!("{} exited before it got ready", name);
+        }
+        let started = Instant::now();
+        let result = probe(&policy, dir).await;
+        let e = match result {
+            Ok(()) => {
+                // the first passing check, the release has been healthy
+                checked(dir, &Ok(()), started);
+                info!("{} is ready", name);
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        if Instant::now() >= deadline {
+            anyhow::bail!("{} not ready after {:?}: {}", name, policy.ready_timeout, e);
+        }
+        tokio::time::sleep(READY_POLL).await;
+    }
+}
+
+// recent checks of an app, for the health server
+pub fn history(app: &str) -> Vec<Check> {
+    HISTORY
+        .lock()
+        .unwrap()
+        .get(app)
+        .map(|h| h.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+// a check of the app in `dir`, for its history and the metrics
+fn checked(dir: &Path, result: &Result<()>, started: Instant) {
+    let app = dir.file_name().unwrap_or_default().to_string_lossy();
+    crate::metrics::health_check(&app, result.is_ok(), started.elapsed());
+    record(
+        &app,
+        Check {
+            timestamp: chrono::Utc::now().timestamp(),
+            ok: result.is_ok(),
+            latency_ms: started.elapsed().as_millis() as u64,
+            message: result
+                .as_ref()
+                .err()
+                .map(|e| e.to_string())
+                .unwrap_or_default(),
+        },
+    );
+}
+
+fn record(app: &str, check: Check) {
+    let mut history = HISTORY.lock().unwrap();
+    let checks = history.entry(app.to_string()).or_default();
+    if checks.len() == HISTORY_LEN {
+        checks.pop_front();
+    }
+    checks.push_back(check);
+}
+
+// Keeps probing the app in `dir` while it runs. `fresh`: just deployed,
+// so on_failure = "rollback" may go back to the previous release.
+pub fn watch(dir: &Path, config: &AppConfig, fresh: bool) {
+    let policy = match Policy::from_config(config, dir) {
+        Ok(Some(p)) => p,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Not health checking {}: {}", config.app.name, e);
+            return;
+        }
+    };
+
+    let generation = {
+        let mut watchers = WATCHERS.lock().unwrap();
+        let g = watchers.entry(dir.to_path_buf()).or_default();
+        *g += 1;
+        *g
+    };
+    let dir = dir.to_path_buf();
+    let name = config.app.name.clone();
+    tokio::spawn(async move {
+        supervise(dir, name, policy, generation, fresh).await;
+    });
+}
+
+// app stopped, its watcher ends at the next tick
+pub fn unwatch(dir: &Path) {
+    WATCHERS.lock().unwrap().remove(dir);
+}
+
+fn current(dir: &Path, generation: u64) -> bool {
+    WATCHERS.lock().unwrap().get(dir) == Some(&generation)
+}
+
+async fn supervise(dir: PathBuf, name: String, policy: Policy, generation: u64, fresh: bool) {
+    let mut failures = 0;
+    // a deploy saves a release that passed its readiness check as healthy
+    let mut ever_healthy = load_state(&dir)
+        .ok()
+        .flatten()
+        .is_some_and(|s| s.health.as_deref() == Some("healthy"));
+    let app = dir
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+
+    loop {
+        tokio::time::sleep(policy.interval).await;
+        if !current(&dir, generation) {
+            return;
+        }
+        let mut state = match load_state(&dir) {
+            Ok(Some(s)) if s.status == "running" => s,
+            _ => return,
+        };
+
+        let started = Instant::now();
+        let result = match state.pid {
+            Some(pid) if !crate::process::alive(pid) => {
+                Err(anyhow::anyhow!("process {} is gone", pid))
+            }
+            _ => probe(&policy, &dir).await,
+        };
+        checked(&dir, &result, started);
+
+        let e = match result {
+            Ok(()) => {
+                failures = 0;
+                ever_healthy = true;
+                if state.health.as_deref() != Some("healthy") {
+                    info!("{} is healthy", name);
+                    set_health(&dir, &mut state, "healthy");
+                }
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        failures += 1;
+        warn!(
+            "Health check of {} failed ({}/{}): {}",
+            name, failures, policy.threshold, e
+        );
+        if failures < policy.threshold {
+            continue;
+        }
+        failures = 0;
+
+        let action = match policy.on_failure.as_str() {
+            "rollback" if fresh && !ever_healthy => "rollback",
+            "degraded" => "degraded",
+            _ => "restart",
+        };
+        let hook_dir = dir.clone();
+        let app = app.clone();
+        let done = tokio::task::spawn_blocking(move || {
+            if let Err(e) = load_app_config(&hook_dir)
+                .and_then(|c| crate::hooks::run_for_app(&c, &hook_dir, "on_health_fail"))
+            {
+                warn!("{}", e);
+            }
+            match action {
+                "rollback" => crate::server::rollback_app(&app)
+                    .or_else(|e| {
+                        warn!("Can't roll back {}: {}, restarting instead", app, e);
+                        crate::server::restart_app(&app)
+                    })
+                    .map(|_| true),
+                "restart" => crate::server::restart_app(&app).map(|_| true),
+                _ => Ok(false),
+            }
+        })
+        .await;
+
+        match done {
+            // a restart started a new watcher
+            Ok(Ok(true)) => {
+                info!("{}: {} after failed health checks", name, action);
+                if let Some(restarts) = crash_loop(&dir) {
+                    crate::notify::send(
+                        &dir,
+                        "crash_loop",
+                        format!("restarted {} times in {:?}: {}", restarts, CRASH_WINDOW, e),
+                    );
+                }
+                return;
+            }
+            Ok(Ok(false)) => {
+                if state.health.as_deref() != Some("degraded") {
+                    warn!("{} is degraded", name);
+                    set_health(&dir, &mut state, "degraded");
+                }
+            }
+            Ok(Err(e)) => {
+                warn!("Can't {} {}: {}", action, name, e);
+                set_health(&dir, &mut state, "unhealthy");
+            }
+            Err(e) => warn!("Health action for {} panicked: {}", name, e),
+        }
+    }
+}
+
+// Counts a restart by the watcher. Some(restarts) once there were
+// CRASH_RESTARTS within CRASH_WINDOW, at most once per window.
+fn crash_loop(dir: &Path) -> Option<usize> {
+    let mut restarts = RESTARTS.lock().unwrap();
+    let app = restarts.entry(dir.to_path_buf()).or_default();
+    let now = Instant::now();
+    app.times.retain(|t| now.duration_since(*t) < CRASH_WINDOW);
+    app.times.push_back(now);
+    let recent = app
+        .alerted
+        .is_some_and(|t| now.duration_since(t) < CRASH_WINDOW);
+    if app.times.len() < CRASH_RESTARTS || recent {
+        return None;
+    }
+    app.alerted = Some(now);
+    Some(app.times.len())
+}
+
+fn set_health(dir: &PathBuf, state: &mut common::AppState, health: &str) {
+    // a fresh release turning healthy is no news
+    match state.health.as_deref() {
+        Some(old) if old != health => {
+            crate::notify::send(dir, "health", format!("{} (was {})", health, old))
+        }
+        None if health != "healthy" => crate::notify::send(dir, "health", health),
+        _ => {}
+    }
+    state.health = Some(health.to_string());
+    if let Err(e) = save_state(dir, state) {
+        warn!("Can't save state of {}: {}", state.name, e);
+    }
+}
@@ -1,5 +1,10 @@
 use anyhow::Result;
-use axum::{Router, extract::State, http::StatusCode, response::Json};
+use axum::{
+    Router,
+    extract::State,
+    http::{StatusCode, header},
+    response::{IntoResponse, Json},
+};
 use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -19,16 +24,41 @@ struct HealthResponse {
     app: String,
     pid: Option<u32>,
     timestamp: i64,
+    // periodic checks from [health], oldest first
+    checks: Vec<crate::health::Check>,
+    process: Option<crate::process::Stats>,
 }
 
-pub async fn run(pids: Arc<RwLock<HashMap<String, Option<u32>>>>) -> Result<()> {
+#[derive(Serialize)]
+struct AllHealthResponse {
+    // unhealthy when any app that should run isn't
+    status: String,
+    timestamp: i64,
+    apps: Vec<HealthResponse>,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    daemon: String,
+    #[serde(flatten)]
+    device: common::DeviceStatus,
+    // includes the last deploy of each
+    apps: Vec<common::AppState>,
+}
+
+pub async fn run(
+    pids: Arc<RwLock<HashMap<String, Option<u32>>>>,
+    listener: TcpListener,
+) -> Result<()> {
     let state = AppState { pids };
     let app = Router::new()
+        .route("/health", axum::routing::get(all_handler))
         .route("/health/:app_name", axum::routing::get(handler))
+        .route("/status", axum::routing::get(status_handler))
+        .route("/metrics", axum::routing::get(metrics_handler))
         .with_state(state);
 
-    let listener = TcpListener::bind("0.0.0.0:7531").await?;
-    info!("Health server on :7531");
+    info!("Health server on {}", listener.local_addr()?);
 
     tokio::spawn(async move {
         axum::serve(listener, app).await.unwrap();
@@ -41,45 +71,101 @@ async fn handler(
     State(state): State<AppState>,
     axum::extract::Path(app_name): axum::extract::Path<String>,
 ) -> Result<Json<HealthResponse>, StatusCode> {
-    let pids = state.pids.read().await;
+    let pid = {
+        let pids = state.pids.read().await;
+        // Get PID for this app
+        pids.get(&app_name).and_then(|p| *p)
+    };
 
-    // Get PID for this app
-    let pid = pids.get(&app_name).and_then(|p| *p);
+    // checks are kept by app directory, the url has the app's name
+    let name = app_name.clone();
+    let dir = tokio::task::spawn_blocking(move || {
+        crate::sysinfo::apps_by_dir()
+            .into_iter()
+            .find(|(dir, state)| *dir == name || state.name == name)
+            .map(|(dir, _)| dir)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .unwrap_or_else(|| app_name.clone());
 
+    Ok(Json(app_health(app_name, &dir, pid)))
+}
+
+fn app_health(app: String, dir: &str, pid: Option<u32>) -> HealthResponse {
     // Check if process is still running
-    let is_alive = pid.map(|p| is_process_alive(p)).unwrap_or(false);
+    let is_alive = pid.is_some_and(crate::process::alive);
 
-    let response = HealthResponse {
+    HealthResponse {
         status: if is_alive { "healthy" } else { "unhealthy" }.to_string(),
-        app: app_name,
+        checks: crate::health::history(dir),
+        app,
         pid,
         timestamp: chrono::Utc::now().timestamp(),
-    };
+        process: pid
+            .filter(|_| is_alive)
+            .and_then(|p| crate::process::stats(p).ok()),
+    }
+}
+
+// every deployed app, stopped ones included
+async fn all_handler() -> Json<AllHealthResponse> {
+    let states = tokio::task::spawn_blocking(crate::sysinfo::apps_by_dir)
+        .await
+        .unwrap_or_default();
 
-    Ok(Json(response))
+    let apps: Vec<HealthResponse> = states
+        .into_iter()
+        .map(|(dir, state)| {
+            let mut health = app_health(state.name, &dir, state.pid);
+            if state.status != "running" {
+                health.status = state.status;
+            } else if health.status == "healthy"
+                && let Some(verdict) = state.health
+            {
+                // degraded/unhealthy per the periodic checks
+                health.status = verdict;
+            }
+            health
+        })
+        .collect();
+
+    let healthy = apps
+        .iter()
+        .all(|a| !matches!(a.status.as_str(), "unhealthy" | "degraded" | "lost"));
+    Json(AllHealthResponse {
+        status: if healthy { "healthy" } else { "unhealthy" }.to_string(),
+        timestamp: chrono::Utc::now().timestamp(),
+        apps,
+    })
 }
 
-fn is_process_alive(pid: u32) -> bool {
-    use std::process::Command;
+async fn status_handler() -> Result<Json<StatusResponse>, StatusCode> {
+    let (device, apps) =
+        tokio::task::spawn_blocking(|| (crate::sysinfo::device_status(), crate::sysinfo::apps()))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    // Linux/Unix: check if process exists
-    let output = Command::new("kill")
-        .arg("-0") // Doesn't actually kill, just checks
-        .arg(pid.to_string())
-        .output();
+    Ok(Json(StatusResponse {
+        daemon: format!("flared {}", env!("CARGO_PKG_VERSION")),
+        device,
+        apps,
+    }))
+}
 
-    match output {
-        Ok(out) => out.status.success(),
-        Err(_) => false,
-    }
+async fn metrics_handler() -> Result<impl IntoResponse, StatusCode> {
+    let body = tokio::task::spawn_blocking(crate::metrics::render)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body))
 }
 
 // Called when app starts/stops
-pub fn update_pid(
+pub async fn update_pid(
     pids: &Arc<RwLock<HashMap<String, Option<u32>>>>,
     app_name: &str,
     pid: Option<u32>,
 ) {
-    let mut pids = pids.blocking_write();
+    let mut pids = pids.write().await;
     pids.insert(app_name.to_string(), pid);
 }
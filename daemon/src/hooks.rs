@@ -1,28 +1,108 @@
-use common::AppConfig;
-use std::path::PathBuf;
+use anyhow::Result;
+use common::{AppConfig, HooksSection};
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use tracing::info;
-
-pub fn run_pre(config: &AppConfig, dir: &PathBuf) {
-    if let Some(hooks) = &config.hooks {
-        if let Some(cmd) = &hooks.pre_deploy {
-            info!("Pre-deploy: {}", cmd);
-            let _ = Command::new("sh")
-                .args(["-c", cmd])
-                .current_dir(dir)
-                .status();
-        }
+use tracing::{info, warn};
+
+use crate::identity::Identity;
+
+// what a hook gets told about the release it runs for
+pub struct Context<'a> {
+    pub config: &'a AppConfig,
+    pub dir: &'a PathBuf,
+    pub identity: Option<&'a Identity>,
+    pub previous: Option<&'a Path>,
+    pub commit: Option<&'a str>,
+}
+
+fn command<'h>(hooks: &'h HooksSection, point: &str) -> Option<&'h String> {
+    match point {
+        "pre_deploy" => hooks.pre_deploy.as_ref(),
+        "pre_build" => hooks.pre_build.as_ref(),
+        "post_build" => hooks.post_build.as_ref(),
+        "pre_start" => hooks.pre_start.as_ref(),
+        "post_start" => hooks.post_start.as_ref(),
+        "post_deploy" => hooks.post_deploy.as_ref(),
+        "pre_stop" => hooks.pre_stop.as_ref(),
+        "on_rollback" => hooks.on_rollback.as_ref(),
+        "on_health_fail" => hooks.on_health_fail.as_ref(),
+        _ => None,
     }
 }
 
-pub fn run_post(config: &AppConfig, dir: &PathBuf) {
-    if let Some(hooks) = &config.hooks {
-        if let Some(cmd) = &hooks.post_deploy {
-            info!("Post-deploy: {}", cmd);
-            let _ = Command::new("sh")
-                .args(["-c", cmd])
-                .current_dir(dir)
-                .status();
+// Runs the hook for `point`, if any. A failing hook is an error unless
+// [hooks] continue_on_error is set, then it's only logged.
+pub fn run(ctx: &Context, point: &str) -> Result<()> {
+    let Some(hooks) = &ctx.config.hooks else {
+        return Ok(());
+    };
+    let Some(cmd) = command(hooks, point) else {
+        return Ok(());
+    };
+    info!("Hook {}: {}", point, cmd);
+
+    let mut hook = Command::new("sh");
+    hook.args(["-c", cmd]).current_dir(ctx.dir);
+    hook.env("FLARE_HOOK", point)
+        .env("FLARE_APP", &ctx.config.app.name)
+        .env("FLARE_VERSION", &ctx.config.app.version)
+        .env("FLARE_RELEASE_DIR", ctx.dir)
+        .env(
+            "FLARE_PREVIOUS_RELEASE",
+            ctx.previous.unwrap_or(Path::new("")),
+        )
+        .env("FLARE_COMMIT", ctx.commit.unwrap_or_default());
+    if let Some(id) = ctx.identity {
+        id.apply(&mut hook);
+    }
+
+    let phase = format!("{} hook", point);
+    let result = crate::oneshot::timeout(hooks.timeout.as_deref(), ctx.config)
+        .and_then(|limit| crate::oneshot::run(&mut hook, &phase, limit))
+        .and_then(|status| {
+            if !status.success() {
+                anyhow::bail!("{} failed ({})", phase, status);
+            }
+            Ok(())
+        });
+
+    match result {
+        Err(e) if hooks.continue_on_error => {
+            warn!("{}, continuing", e);
+            Ok(())
         }
+        r => r,
+    }
+}
+
+// hooks outside a deploy: the same release, looked up from disk
+pub fn run_for_app(config: &AppConfig, dir: &PathBuf, point: &str) -> Result<()> {
+    let has_hook = config
+        .hooks
+        .as_ref()
+        .is_some_and(|h| command(h, point).is_some());
+    if !has_hook {
+        return Ok(());
     }
+
+    let identity = crate::identity::for_app(config, dir)?;
+    let previous = latest_backup(dir);
+    let state = common::load_state(dir).ok().flatten();
+    let ctx = Context {
+        config,
+        dir,
+        identity: identity.as_ref(),
+        previous: previous.as_deref(),
+        commit: state.as_ref().and_then(|s| s.commit.as_deref()),
+    };
+    run(&ctx, point)
+}
+
+// newest of versions/<timestamp>
+pub fn latest_backup(dir: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(dir.join("versions"))
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .max()
 }
@@ -0,0 +1,270 @@
+use anyhow::Result;
+use common::AppConfig;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::info;
+
+// user an app's processes (build, hooks, run) are started as
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: Option<PathBuf>,
+}
+
+// None: the app runs as flared itself
+pub fn for_app(config: &AppConfig, dir: &Path) -> Result<Option<Identity>> {
+    let Some(run) = &config.run else {
+        return Ok(None);
+    };
+
+    let user = if run.create_user {
+        let name = user_name(&config.app.name);
+        ensure_user(&name, dir)?;
+        name
+    } else {
+        match &run.user {
+            Some(u) => u.clone(),
+            None if run.group.is_none() => return Ok(None),
+            // group only: keep our uid
+            None => unsafe { libc::geteuid() }.to_string(),
+        }
+    };
+
+    let mut identity = lookup_user(&user)?;
+    if let Some(group) = &run.group {
+        identity.gid = lookup_group(group)?;
+    }
+    check_privileges(&identity)?;
+    Ok(Some(identity))
+}
+
+// "flare-<app>", within the 32 chars useradd takes
+fn user_name(app: &str) -> String {
+    let slug: String = app
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let mut name = format!("flare-{}", slug.trim_matches('-'));
+    name.truncate(32);
+    name
+}
+
+fn check_privileges(id: &Identity) -> Result<()> {
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if euid != 0 && (id.uid != euid || id.gid != egid) {
+        anyhow::bail!(
+            "flared runs as uid {} and can't switch to user {}: run it as root or drop [run] user/group",
+            euid,
+            id.name
+        );
+    }
+    Ok(())
+}
+
+fn ensure_user(name: &str, dir: &Path) -> Result<()> {
+    if lookup_user(name).is_ok() {
+        return Ok(());
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        anyhow::bail!(
+            "Creating user {} needs flared to run as root (or create it and set [run] user)",
+            name
+        );
+    }
+
+    info!("Creating system user {}", name);
+    let home = dir.to_string_lossy();
+    let useradd = Command::new("useradd")
+        .args(["--system", "--user-group", "--no-create-home"])
+        .args(["--home-dir", &home, "--shell", "/usr/sbin/nologin", name])
+        .status();
+    let status = match useradd {
+        Ok(s) => s,
+        // busybox (alpine, openwrt)
+        Err(_) => Command::new("adduser")
+            .args(["-S", "-D", "-H", "-h", &home, "-s", "/sbin/nologin", name])
+            .status()
+            .map_err(|e| anyhow::anyhow!("Neither useradd nor adduser work: {}", e))?,
+    };
+    if !status.success() {
+        anyhow::bail!("Can't create user {}", name);
+    }
+    Ok(())
+}
+
+// name or numeric uid
+fn lookup_user(user: &str) -> Result<Identity> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut found = std::ptr::null_mut();
+
+    let numeric = user.parse::<u32>().ok();
+    let rc = unsafe {
+        match numeric {
+            Some(uid) => libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found),
+            None => {
+                let name = CString::new(user)?;
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            }
+        }
+    };
+
+    if rc != 0 || found.is_null() {
+        // a bare uid doesn't need a passwd entry
+        if let Some(uid) = numeric {
+            return Ok(Identity {
+                name: user.to_string(),
+                uid,
+                gid: uid,
+                home: None,
+            });
+        }
+        anyhow::bail!("No such user: {}", user);
+    }
+
+    let field =
+        |p: *const libc::c_char| unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned();
+    Ok(Identity {
+        name: field(pwd.pw_name),
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        home: Some(PathBuf::from(field(pwd.pw_dir))),
+    })
+}
+
+// name or numeric gid
+fn lookup_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut found = std::ptr::null_mut();
+    let name = CString::new(group)?;
+    let rc = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if rc != 0 || found.is_null() {
+        anyhow::bail!("No such group: {}", group);
+    }
+    Ok(grp.gr_gid)
+}
+
+impl Identity {
+    // USER/HOME the way a login would set them, unless the app sets its own
+    pub fn env(&self, vars: &mut HashMap<String, String>) {
+        vars.entry("USER".into())
+            .or_insert_with(|| self.name.clone());
+        vars.entry("LOGNAME".into())
+            .or_insert_with(|| self.name.clone());
+        if let Some(home) = &self.home {
+            vars.entry("HOME".into())
+                .or_insert_with(|| home.to_string_lossy().into());
+        }
+    }
+
+    // setgid/setuid in the child right before exec, supplementary groups are dropped
+    pub fn apply(&self, cmd: &mut Command) {
+        cmd.uid(self.uid).gid(self.gid);
+    }
+
+    // all of `dir` goes to the app, for volumes
+    pub fn own(&self, dir: &Path) -> Result<()> {
+        chown_tree(dir, self.uid, self.gid)
+            .map_err(|e| anyhow::anyhow!("Can't hand {:?} to {}: {}", dir, self.name, e))
+    }
+
+    // The release is the app's to write, builds put their output there. The
+    // directory itself stays ours, group-writable with the sticky bit, so the
+    // app can add files but not replace the ones we run it from.
+    pub fn own_release(&self, dir: &Path) -> Result<()> {
+        self.hand_over(dir)
+            .map_err(|e| anyhow::anyhow!("Can't hand {:?} to {}: {}", dir, self.name, e))
+    }
+
+    fn hand_over(&self, dir: &Path) -> std::io::Result<()> {
+        let ours = unsafe { libc::geteuid() };
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            // state/pid files halfway through being replaced
+            if name.starts_with(".state.toml.") || name.starts_with(".flare.pid.") {
+                continue;
+            }
+            // rollbacks run what's in there, the app doesn't get to see it
+            if name == "versions" {
+                keep_private(&path)?;
+                continue;
+            }
+            let Some(&(_, mode)) = CONTROL_FILES.iter().find(|(n, _)| *n == name) else {
+                chown_tree(&path, self.uid, self.gid)?;
+                continue;
+            };
+
+            if std::fs::symlink_metadata(&path)?.is_symlink() {
+                // only the app puts links there, we write these with a rename
+                if matches!(name.as_ref(), "state.toml" | "flare.pid") {
+                    std::fs::remove_file(&path)?;
+                    continue;
+                }
+                return Err(std::io::Error::other(format!(
+                    "{} must be a file, not a symlink",
+                    name
+                )));
+            }
+            std::os::unix::fs::lchown(&path, Some(ours), Some(self.gid))?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+        }
+        std::os::unix::fs::lchown(dir, Some(ours), Some(self.gid))?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o1775))
+    }
+}
+
+// ours and only ours; a link the app put there instead goes
+pub fn keep_private(path: &Path) -> std::io::Result<()> {
+    if !std::fs::symlink_metadata(path)?.is_dir() {
+        return std::fs::remove_file(path);
+    }
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700))
+}
+
+// what flared reads back and acts on as root, the app may only read them
+const CONTROL_FILES: &[(&str, u32)] = &[
+    ("flare.toml", 0o644),
+    ("state.toml", 0o644),
+    ("flare.pid", 0o644),
+    (".env", 0o640),
+];
+
+fn chown_tree(path: &Path, uid: u32, gid: u32) -> std::io::Result<()> {
+    // symlinks themselves, never what they point to
+    std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            chown_tree(&entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,363 @@
+use anyhow::Result;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Args, Command};
+
+#[derive(clap::Args, Debug)]
+pub struct InstallArgs {
+    // systemd user service, data in ~/.flare
+    #[arg(long, conflicts_with = "system")]
+    pub user: bool,
+    // system-wide service, data in /var/lib/flare (default for root)
+    #[arg(long)]
+    pub system: bool,
+    // "systemd", "openrc" or "sysv", detected when not set
+    #[arg(long)]
+    pub init: Option<String>,
+    // everything is written below this prefix (packaging, tests)
+    #[arg(long, default_value = "/")]
+    pub root: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Init {
+    Systemd,
+    OpenRc,
+    SysV,
+}
+
+// where things go, paths as seen by the running system (without --root)
+struct Layout {
+    user: bool,
+    init: Init,
+    service: PathBuf,
+    data_dir: PathBuf,
+    exe: PathBuf,
+}
+
+pub fn run(cmd: &Command, args: &Args) -> Result<()> {
+    match cmd {
+        Command::Install(target) => install(&layout(target, args)?, &target.root),
+        Command::Uninstall { target, purge } => {
+            uninstall(&layout(target, args)?, &target.root, *purge)
+        }
+    }
+}
+
+fn layout(target: &InstallArgs, args: &Args) -> Result<Layout> {
+    let user = target.user || (!target.system && unsafe { libc::geteuid() } != 0);
+
+    let init = match target.init.as_deref() {
+        Some("systemd") => Init::Systemd,
+        Some("openrc") => Init::OpenRc,
+        Some("sysv") => Init::SysV,
+        Some(other) => anyhow::bail!("Unknown init system: {}", other),
+        None => detect_init(&target.root),
+    };
+    if user && init != Init::Systemd {
+        anyhow::bail!("--user needs systemd");
+    }
+
+    let home = home_dir();
+    let service = match (init, user) {
+        (Init::Systemd, true) => home.join(".config/systemd/user/flared.service"),
+        (Init::Systemd, false) => PathBuf::from("/etc/systemd/system/flared.service"),
+        _ => PathBuf::from("/etc/init.d/flared"),
+    };
+
+    let data_dir = match &args.data_dir {
+        Some(dir) => dir.clone(),
+        None if user => home.join(".flare"),
+        None => PathBuf::from("/var/lib/flare"),
+    };
+
+    Ok(Layout {
+        user,
+        init,
+        service,
+        data_dir,
+        exe: std::env::current_exe()?,
+    })
+}
+
+fn home_dir() -> PathBuf {
+    common::flare_dir()
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+}
+
+fn detect_init(root: &Path) -> Init {
+    if under(root, Path::new("/run/systemd/system")).exists() {
+        Init::Systemd
+    } else if under(root, Path::new("/sbin/openrc-run")).exists() {
+        Init::OpenRc
+    } else {
+        Init::SysV
+    }
+}
+
+// `path` moved below the install prefix
+fn under(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+fn install(layout: &Layout, root: &Path) -> Result<()> {
+    let data_dir = under(root, &layout.data_dir);
+    std::fs::create_dir_all(data_dir.join("apps"))?;
+    // daemon tokens live here
+    std::fs::set_permissions(&data_dir, std::fs::Permissions::from_mode(0o700))?;
+    println!("Data directory {}", layout.data_dir.display());
+
+    if crate::tls::write_identity(&data_dir)? {
+        println!("Generated identity certificate");
+    }
+
+    let service = under(root, &layout.service);
+    std::fs::create_dir_all(service.parent().unwrap())?;
+    let (content, mode) = match layout.init {
+        Init::Systemd => (systemd_unit(layout), 0o644),
+        Init::OpenRc => (openrc_script(layout), 0o755),
+        Init::SysV => (sysv_script(layout), 0o755),
+    };
+    std::fs::write(&service, content)
+        .map_err(|e| anyhow::anyhow!("Can't write {:?}: {}", service, e))?;
+    std::fs::set_permissions(&service, std::fs::Permissions::from_mode(mode))?;
+    println!("Wrote {}", layout.service.display());
+
+    println!("\nEnable and start it with:");
+    match (layout.init, layout.user) {
+        (Init::Systemd, true) => {
+            println!("  systemctl --user daemon-reload && systemctl --user enable --now flared");
+            println!("  loginctl enable-linger $USER   # keep running after logout");
+        }
+        (Init::Systemd, false) => {
+            println!("  systemctl daemon-reload && systemctl enable --now flared")
+        }
+        (Init::OpenRc, _) => println!("  rc-update add flared default && rc-service flared start"),
+        (Init::SysV, _) => println!("  update-rc.d flared defaults && service flared start"),
+    }
+    Ok(())
+}
+
+fn uninstall(layout: &Layout, root: &Path, purge: bool) -> Result<()> {
+    let service = under(root, &layout.service);
+    if service.exists() {
+        std::fs::remove_file(&service)?;
+        println!("Removed {}", layout.service.display());
+    } else {
+        println!("No service at {}", layout.service.display());
+    }
+
+    if purge {
+        let data_dir = under(root, &layout.data_dir);
+        if data_dir.exists() {
+            std::fs::remove_dir_all(&data_dir)?;
+            println!("Removed {}", layout.data_dir.display());
+        }
+    }
+
+    match (layout.init, layout.user) {
+        (Init::Systemd, true) => println!("Run: systemctl --user disable --now flared"),
+        (Init::Systemd, false) => println!("Run: systemctl disable --now flared"),
+        (Init::OpenRc, _) => println!("Run: rc-update del flared default"),
+        (Init::SysV, _) => println!("Run: update-rc.d flared remove"),
+    }
+    Ok(())
+}
+
+fn systemd_unit(layout: &Layout) -> String {
+    let (after, wanted_by) = if layout.user {
+        ("network.target", "default.target")
+    } else {
+        ("network-online.target", "multi-user.target")
+    };
+
+    format!(
+        "[Unit]
+Description=Flare deploy daemon
+After={after}
+Wants={after}
+
+[Service]
+ExecStart={exe} --data-dir {data}
+Restart=on-failure
+# apps run as children of flared, shutdown.apps in flared.toml decides
+# whether they outlive it, not systemd
+KillMode=process
+TimeoutStopSec=90
+
+[Install]
+WantedBy={wanted_by}
+",
+        exe = layout.exe.display(),
+        data = layout.data_dir.display(),
+    )
+}
+
+fn openrc_script(layout: &Layout) -> String {
+    format!(
+        r#"#!/sbin/openrc-run
+
+description="Flare deploy daemon"
+command="{exe}"
+command_args="--data-dir {data}"
+command_background=true
+pidfile="/run/flared.pid"
+retry="TERM/90/KILL/5"
+
+depend() {{
+    need net
+}}
+"#,
+        exe = layout.exe.display(),
+        data = layout.data_dir.display(),
+    )
+}
+
+fn sysv_script(layout: &Layout) -> String {
+    format!(
+        r#"#!/bin/sh
+### BEGIN INIT INFO
+# Provides:          flared
+# Required-Start:    $network $remote_fs
+# Required-Stop:     $network $remote_fs
+# Default-Start:     2 3 4 5
+# Default-Stop:      0 1 6
+# Short-Description: Flare deploy daemon
+### END INIT INFO
+
+DAEMON="{exe}"
+PIDFILE=/var/run/flared.pid
+
+case "$1" in
+    start)
+        start-stop-daemon --start --background --make-pidfile --pidfile $PIDFILE \
+            --exec "$DAEMON" -- --data-dir "{data}"
+        ;;
+    stop)
+        start-stop-daemon --stop --retry TERM/90/KILL/5 --pidfile $PIDFILE --remove-pidfile
+        ;;
+    restart)
+        "$0" stop
+        "$0" start
+        ;;
+    status)
+        if start-stop-daemon --status --pidfile $PIDFILE; then
+            echo "flared is running"
+        else
+            echo "flared is stopped"
+            exit 3
+        fi
+        ;;
+    *)
+        echo "Usage: $0 {{start|stop|restart|status}}"
+        exit 1
+        ;;
+esac
+"#,
+        exe = layout.exe.display(),
+        data = layout.data_dir.display(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(init: Init, user: bool) -> Layout {
+        Layout {
+            user,
+            init,
+            service: match init {
+                Init::Systemd if user => {
+                    PathBuf::from("/home/me/.config/systemd/user/flared.service")
+                }
+                Init::Systemd => PathBuf::from("/etc/systemd/system/flared.service"),
+                _ => PathBuf::from("/etc/init.d/flared"),
+            },
+            data_dir: PathBuf::from("/var/lib/flare"),
+            exe: PathBuf::from("/usr/local/bin/flared"),
+        }
+    }
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn systemd_unit_under_root() {
+        let root = tempfile::tempdir().unwrap();
+        install(&layout(Init::Systemd, false), root.path()).unwrap();
+
+        let unit = root.path().join("etc/systemd/system/flared.service");
+        let content = std::fs::read_to_string(&unit).unwrap();
+        assert!(content.contains("ExecStart=/usr/local/bin/flared --data-dir /var/lib/flare\n"));
+        assert!(content.contains("After=network-online.target\n"));
+        assert!(content.contains("WantedBy=multi-user.target\n"));
+        assert!(content.contains("KillMode=process\n"));
+        assert_eq!(mode(&unit), 0o644);
+
+        let data = root.path().join("var/lib/flare");
+        assert!(data.join("apps").is_dir());
+        assert_eq!(mode(&data), 0o700);
+    }
+
+    #[test]
+    fn systemd_user_unit() {
+        let content = systemd_unit(&layout(Init::Systemd, true));
+        assert!(content.contains("After=network.target\n"));
+        assert!(content.contains("WantedBy=default.target\n"));
+    }
+
+    #[test]
+    fn init_scripts_are_executable() {
+        for init in [Init::OpenRc, Init::SysV] {
+            let root = tempfile::tempdir().unwrap();
+            install(&layout(init, false), root.path()).unwrap();
+
+            let script = root.path().join("etc/init.d/flared");
+            let content = std::fs::read_to_string(&script).unwrap();
+            assert_eq!(mode(&script), 0o755);
+            assert!(content.contains("/usr/local/bin/flared"));
+            assert!(content.contains("--data-dir"));
+            match init {
+                Init::OpenRc => assert!(content.starts_with("#!/sbin/openrc-run\n")),
+                _ => assert!(content.starts_with("#!/bin/sh\n### BEGIN INIT INFO")),
+            }
+        }
+    }
+
+    #[test]
+    fn uninstall_purge() {
+        let root = tempfile::tempdir().unwrap();
+        let layout = layout(Init::Systemd, false);
+        install(&layout, root.path()).unwrap();
+
+        uninstall(&layout, root.path(), false).unwrap();
+        assert!(
+            !root
+                .path()
+                .join("etc/systemd/system/flared.service")
+                .exists()
+        );
+        assert!(root.path().join("var/lib/flare").exists());
+
+        uninstall(&layout, root.path(), true).unwrap();
+        assert!(!root.path().join("var/lib/flare").exists());
+    }
+
+    #[test]
+    fn detects_init_below_root() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(detect_init(root.path()), Init::SysV);
+
+        std::fs::create_dir_all(root.path().join("sbin")).unwrap();
+        std::fs::write(root.path().join("sbin/openrc-run"), "").unwrap();
+        assert_eq!(detect_init(root.path()), Init::OpenRc);
+
+        std::fs::create_dir_all(root.path().join("run/systemd/system")).unwrap();
+        assert_eq!(detect_init(root.path()), Init::Systemd);
+    }
+}
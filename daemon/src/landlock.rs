@@ -0,0 +1,191 @@
+use anyhow::Result;
+use common::AppConfig;
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use tracing::{info, warn};
+
+// uapi/linux/landlock.h, libc doesn't carry these
+const CREATE_RULESET_VERSION: u32 = 1;
+const RULE_PATH_BENEATH: u32 = 1;
+
+const EXECUTE: u64 = 1 << 0;
+const WRITE_FILE: u64 = 1 << 1;
+const READ_FILE: u64 = 1 << 2;
+const READ_DIR: u64 = 1 << 3;
+// execute .. make_sym, all of ABI 1
+const ABI_1: u64 = (1 << 13) - 1;
+const REFER: u64 = 1 << 13; // ABI 2
+const TRUNCATE: u64 = 1 << 14; // ABI 3
+
+const READ: u64 = EXECUTE | READ_FILE | READ_DIR;
+// rights that make sense on a file rather than a directory
+const FILE: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+
+// readable even when not listed, an app can't start without them; /run
+// because /etc/resolv.conf tends to link into it
+const READ_ALWAYS: &[&str] = &[
+    "/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/etc", "/proc", "/dev", "/run",
+];
+const WRITE_ALWAYS: &[&str] = &["/dev/null", "/dev/zero", "/dev/full", "/dev/tty", "/tmp"];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+// Limits the app to [isolation] allow_read/allow_write, its own directory
+// and the system paths above. Kernels without Landlock just get a warning.
+pub fn apply(cmd: &mut Command, config: &AppConfig, dir: &Path) -> Result<()> {
+    let Some(isolation) = &config.isolation else {
+        return Ok(());
+    };
+    if isolation.allow_read.is_empty() && isolation.allow_write.is_empty() {
+        return Ok(());
+    }
+    match isolation.r#type.as_str() {
+        "sandbox" | "chroot" => {
+            warn!(
+                "allow_read/allow_write are ignored with the sandbox, it has its own filesystem view"
+            );
+            return Ok(());
+        }
+        // systemd-run has to reach the service manager first
+        "systemd" => {
+            warn!("allow_read/allow_write are ignored with isolation = \"systemd\"");
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let abi = abi_version();
+    if abi < 1 {
+        warn!(
+            "Landlock isn't available on this kernel, {} runs without allow_read/allow_write limits",
+            config.app.name
+        );
+        return Ok(());
+    }
+
+    let mut handled = ABI_1;
+    if abi >= 2 {
+        handled |= REFER;
+    }
+    if abi >= 3 {
+        handled |= TRUNCATE;
+    }
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        anyhow::bail!(
+            "Can't create Landlock ruleset: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+    for path in READ_ALWAYS {
+        add_rule(&ruleset, Path::new(path), READ & handled, false)?;
+    }
+    for path in WRITE_ALWAYS {
+        add_rule(&ruleset, Path::new(path), handled, false)?;
+    }
+    add_rule(&ruleset, dir, handled, true)?;
+    // relative paths are inside the app directory
+    for path in &isolation.allow_read {
+        add_rule(&ruleset, &dir.join(path), READ & handled, true)?;
+    }
+    for path in &isolation.allow_write {
+        add_rule(&ruleset, &dir.join(path), handled, true)?;
+    }
+    info!("Landlock (ABI {}) limits {}", abi, config.app.name);
+
+    // the ruleset fd is close-on-exec, it only has to live until then
+    unsafe {
+        cmd.pre_exec(move || {
+            // lets an unprivileged process restrict itself
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                || libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+// 0 when the kernel doesn't know Landlock or has it turned off
+fn abi_version() -> i64 {
+    let v = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    v.max(0)
+}
+
+fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64, listed: bool) -> Result<()> {
+    let file = match File::options()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+        .open(path)
+    {
+        Ok(f) => f,
+        Err(e) => {
+            if listed {
+                warn!("Skipping Landlock rule for {:?}: {}", path, e);
+            }
+            return Ok(());
+        }
+    };
+
+    let access = if file.metadata()?.is_dir() {
+        access
+    } else {
+        access & FILE
+    };
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: file.as_raw_fd(),
+    };
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    };
+    if rc != 0 {
+        anyhow::bail!(
+            "Can't add Landlock rule for {:?}: {}",
+            path,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use anyhow::Result;
+use std::os::unix::fs::PermissionsExt;
+use tokio::net::UnixListener;
+use tracing::{error, info, warn};
+
+use crate::server::{Peer, Shared};
+
+// Admin socket for on-device use: same protocol as TCP, no TLS and no tokens.
+// Only root and the daemon's own user get in (SO_PEERCRED).
+pub async fn run(shared: Shared) -> Result<()> {
+    let path = crate::config::get().socket_path();
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    // stale socket from a previous run
+    let _ = std::fs::remove_file(&path);
+
+    let listener = UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Local admin socket on {:?}", path);
+
+    let own_uid = unsafe { libc::geteuid() };
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let uid = match stream.peer_cred() {
+            Ok(cred) => cred.uid(),
+            Err(e) => {
+                warn!("Can't read local peer credentials: {}", e);
+                continue;
+            }
+        };
+
+        if uid != 0 && uid != own_uid {
+            warn!("Rejected local peer with uid {}", uid);
+            continue;
+        }
+
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::server::handle(stream, Peer::Local(uid), shared).await {
+                error!("Local handler error: {}", e);
+            }
+        });
+    }
+}
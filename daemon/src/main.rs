@@ -1,24 +1,92 @@
+mod adopt;
+mod cgroup;
+mod config;
 mod database;
 mod deploy;
 mod discovery;
 mod env_loader;
 mod gateway;
+mod health;
 mod health_server;
 mod hooks;
+mod identity;
+mod install;
+mod landlock;
+mod local;
+mod metrics;
+mod notify;
+mod oneshot;
+mod process;
+mod pushgateway;
+mod ratelimit;
+mod s3;
+mod sandbox;
 mod server;
+mod shutdown;
+mod storage;
+mod sysinfo;
+#[cfg(test)]
+mod testing;
 mod tls;
+mod transfer;
+mod upgrade;
+
+use clap::Parser;
+use tracing_subscriber::EnvFilter;
+
+fn main() {
+    // sandbox launcher must not start a runtime, see sandbox::main
+    if std::env::args().nth(1).as_deref() == Some(sandbox::ARG) {
+        sandbox::main();
+    }
+    daemon();
+}
 
 #[tokio::main]
-async fn main() {
-    let args: Vec<String> = std::env::args().collect();
+async fn daemon() {
+    let args = config::Args::parse();
+    upgrade::init(&args);
+
+    if let Some(cmd) = &args.command {
+        if let Err(e) = install::run(cmd, &args) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let config = match config::load(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            if let Some(e) = upgrade::rollback() {
+                eprintln!("Rollback failed: {}", e);
+            }
+            std::process::exit(1);
+        }
+    };
 
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+    // RUST_LOG wins over the config file
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log.filter));
+    let logger = tracing_subscriber::fmt().with_env_filter(filter);
+    if config.log.format == "json" {
+        logger.json().init();
+    } else {
+        logger.init();
+    }
+
+    config::init(config);
 
     tracing::info!("Flared starting...");
 
-    if let Err(e) = server::run(7530).await {
+    if let Err(e) = server::run().await {
         tracing::error!("Daemon crashed: {}", e);
+        if let Some(e) = upgrade::rollback() {
+            tracing::error!("Rollback failed: {}", e);
+        }
+        std::process::exit(1);
     }
+
+    tracing::info!("Flared stopped");
 }
@@ -0,0 +1,344 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Write};
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+// Counters since flared started, rendered in the Prometheus text format by
+// the health server at /metrics. Apps are labelled by their app dir name,
+// like events; gateway requests by host.
+
+// what an app exports when [metrics] collect doesn't say
+const SERIES: &[&str] = &["deploys", "restarts", "health", "requests", "cpu", "memory"];
+
+#[derive(Default)]
+struct Summary {
+    count: u64,
+    sum: f64,
+}
+
+impl Summary {
+    fn observe(&mut self, d: Duration) {
+        self.count += 1;
+        self.sum += d.as_secs_f64();
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    // (app, "success" | "failure")
+    deploys: BTreeMap<(String, &'static str), u64>,
+    deploy_seconds: BTreeMap<String, Summary>,
+    restarts: BTreeMap<String, u64>,
+    // (app, "ok" | "fail")
+    checks: BTreeMap<(String, &'static str), u64>,
+    check_seconds: BTreeMap<String, Summary>,
+    // last check passed
+    check_up: BTreeMap<String, bool>,
+    // (host, status code)
+    requests: BTreeMap<(String, u16), u64>,
+    request_seconds: BTreeMap<String, Summary>,
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(Default::default);
+
+pub fn deploy(app: &str, ok: bool, took: Duration) {
+    let mut r = REGISTRY.lock().unwrap();
+    let result = if ok { "success" } else { "failure" };
+    *r.deploys.entry((app.to_string(), result)).or_default() += 1;
+    r.deploy_seconds
+        .entry(app.to_string())
+        .or_default()
+        .observe(took);
+}
+
+pub fn restart(app: &str) {
+    *REGISTRY
+        .lock()
+        .unwrap()
+        .restarts
+        .entry(app.to_string())
+        .or_default() += 1;
+}
+
+pub fn health_check(app: &str, ok: bool, took: Duration) {
+    let mut r = REGISTRY.lock().unwrap();
+    let result = if ok { "ok" } else { "fail" };
+    *r.checks.entry((app.to_string(), result)).or_default() += 1;
+    r.check_seconds
+        .entry(app.to_string())
+        .or_default()
+        .observe(took);
+    r.check_up.insert(app.to_string(), ok);
+}
+
+pub fn request(host: &str, status: u16, took: Duration) {
+    let mut r = REGISTRY.lock().unwrap();
+    *r.requests.entry((host.to_string(), status)).or_default() += 1;
+    r.request_seconds
+        .entry(host.to_string())
+        .or_default()
+        .observe(took);
+}
+
+// what each deployed app lets through, from its [metrics] collect
+struct Filter {
+    // app dir name -> series
+    apps: HashMap<String, Vec<String>>,
+    // gateway host -> app dir name
+    hosts: HashMap<String, String>,
+    // just this app, for its own push
+    only: Option<String>,
+}
+
+impl Filter {
+    fn load(only: Option<&str>) -> Self {
+        let mut filter = Self {
+            apps: HashMap::new(),
+            hosts: HashMap::new(),
+            only: only.map(String::from),
+        };
+        let Ok(entries) = std::fs::read_dir(crate::config::get().apps_dir()) else {
+            return filter;
+        };
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let Ok(config) = common::load_app_config(&entry.path()) else {
+                continue;
+            };
+            let app = entry.file_name().to_string_lossy().into_owned();
+            if let Some(web) = &config.web {
+                filter.hosts.insert(web.domain.clone(), app.clone());
+            }
+            filter.apps.insert(app, collect(&config));
+        }
+        filter
+    }
+
+    // apps flared doesn't know (any more) keep their counters
+    fn allows(&self, app: &str, series: &str) -> bool {
+        if self.only.as_deref().is_some_and(|only| only != app) {
+            return false;
+        }
+        self.apps
+            .get(app)
+            .is_none_or(|s| s.iter().any(|s| s == series))
+    }
+
+    // hosts no app serves only show up in the full listing
+    fn allows_host(&self, host: &str) -> bool {
+        match self.hosts.get(host) {
+            Some(app) => self.allows(app, "requests"),
+            None => self.only.is_none(),
+        }
+    }
+}
+
+// [metrics] collect, all series without it
+fn collect(config: &common::AppConfig) -> Vec<String> {
+    match config.metrics.as_ref().and_then(|m| m.collect.as_ref()) {
+        Some(series) => series
+            .iter()
+            // "http" reads better next to cpu and memory
+            .map(|s| {
+                if s == "http" {
+                    "requests".into()
+                } else {
+                    s.clone()
+                }
+            })
+            .collect(),
+        None => SERIES.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// `name{k="v",...} value`, label values escaped per the text format
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl Display) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect();
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+}
+
+fn summary(
+    out: &mut String,
+    name: &str,
+    key: &str,
+    values: &BTreeMap<String, Summary>,
+    keep: impl Fn(&str) -> bool,
+) {
+    for (k, s) in values.iter().filter(|(k, _)| keep(k)) {
+        sample(out, &format!("{}_sum", name), &[(key, k)], s.sum);
+        sample(out, &format!("{}_count", name), &[(key, k)], s.count);
+    }
+}
+
+// Everything in the Prometheus text exposition format. Reads app configs
+// and /proc, call it off the runtime.
+pub fn render() -> String {
+    render_filtered(&Filter::load(None))
+}
+
+// only the series of one app, what gets pushed for it
+pub fn render_app(app: &str) -> String {
+    render_filtered(&Filter::load(Some(app)))
+}
+
+fn render_filtered(filter: &Filter) -> String {
+    let mut text = String::new();
+    let out = &mut text;
+
+    {
+        let r = REGISTRY.lock().unwrap();
+        // per app series this app doesn't collect
+
+        let name = "flare_deploys_total";
+        header(out, name, "counter", "Deploys by result.");
+        for ((app, result), n) in r
+            .deploys
+            .iter()
+            .filter(|((a, _), _)| filter.allows(a, "deploys"))
+        {
+            sample(out, name, &[("app", app), ("result", result)], n);
+        }
+        let name = "flare_deploy_duration_seconds";
+        header(
+            out,
+            name,
+            "summary",
+            "Time from request to running release.",
+        );
+        summary(out, name, "app", &r.deploy_seconds, |a| {
+            filter.allows(a, "deploys")
+        });
+
+        let name = "flare_app_restarts_total";
+        header(
+            out,
+            name,
+            "counter",
+            "Restarts, by hand or after failed health checks.",
+        );
+        for (app, n) in r
+            .restarts
+            .iter()
+            .filter(|(a, _)| filter.allows(a, "restarts"))
+        {
+            sample(out, name, &[("app", app)], n);
+        }
+
+        let name = "flare_health_checks_total";
+        header(out, name, "counter", "Periodic health checks by result.");
+        for ((app, result), n) in r
+            .checks
+            .iter()
+            .filter(|((a, _), _)| filter.allows(a, "health"))
+        {
+            sample(out, name, &[("app", app), ("result", result)], n);
+        }
+        let name = "flare_health_check_duration_seconds";
+        header(out, name, "summary", "Health probe latency.");
+        summary(out, name, "app", &r.check_seconds, |a| {
+            filter.allows(a, "health")
+        });
+        let name = "flare_health_check_up";
+        header(out, name, "gauge", "1 if the last health check passed.");
+        for (app, up) in r
+            .check_up
+            .iter()
+            .filter(|(a, _)| filter.allows(a, "health"))
+        {
+            sample(out, name, &[("app", app)], *up as u8);
+        }
+
+        let shown = |host: &str| filter.allows_host(host);
+        let name = "flare_gateway_requests_total";
+        header(out, name, "counter", "Gateway requests by host and status.");
+        for ((host, status), n) in r.requests.iter().filter(|((h, _), _)| shown(h)) {
+            sample(
+                out,
+                name,
+                &[("host", host), ("status", &status.to_string())],
+                n,
+            );
+        }
+        let name = "flare_gateway_request_duration_seconds";
+        header(out, name, "summary", "Gateway request latency.");
+        summary(out, name, "host", &r.request_seconds, shown);
+    }
+
+    usage(out, filter);
+    text
+}
+
+// cpu and memory of running apps, right now
+fn usage(out: &mut String, filter: &Filter) {
+    let mut cpu = BTreeMap::new();
+    let mut memory = BTreeMap::new();
+    if let Ok(entries) = std::fs::read_dir(crate::config::get().apps_dir()) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let app = entry.file_name().to_string_lossy().into_owned();
+            let pid = match common::load_state(&entry.path()) {
+                Ok(Some(s)) if s.status == "running" => s.pid,
+                _ => None,
+            };
+            let Some((seconds, bytes)) = pid.and_then(|p| app_usage(&entry.path(), p)) else {
+                continue;
+            };
+            if filter.allows(&app, "cpu") {
+                cpu.insert(app.clone(), seconds);
+            }
+            if filter.allows(&app, "memory") {
+                memory.insert(app, bytes);
+            }
+        }
+    }
+
+    let name = "flare_app_cpu_seconds_total";
+    header(out, name, "counter", "CPU time of the running app.");
+    for (app, seconds) in cpu {
+        sample(out, name, &[("app", &app)], seconds);
+    }
+    let name = "flare_app_memory_bytes";
+    header(out, name, "gauge", "Memory of the running app.");
+    for (app, bytes) in memory {
+        sample(out, name, &[("app", &app)], bytes);
+    }
+}
+
+// (cpu seconds, memory bytes): the app's own cgroup covers everything it
+// forked, without one only the group leader is counted
+fn app_usage(dir: &Path, pid: u32) -> Option<(f64, u64)> {
+    if !crate::process::alive(pid) {
+        return None;
+    }
+    let cgroup = crate::cgroup::path(dir);
+    let from_cgroup = || -> Option<(f64, u64)> {
+        let cpu = std::fs::read_to_string(cgroup.join("cpu.stat")).ok()?;
+        let usec: u64 = cpu
+            .lines()
+            .find_map(|l| l.strip_prefix("usage_usec "))?
+            .trim()
+            .parse()
+            .ok()?;
+        let memory = std::fs::read_to_string(cgroup.join("memory.current")).ok()?;
+        Some((usec as f64 / 1e6, memory.trim().parse().ok()?))
+    };
+    from_cgroup().or_else(|| {
+        crate::process::stats(pid)
+            .ok()
+            .map(|s| (s.cpu_seconds, s.rss_bytes))
+    })
+}
@@ -0,0 +1,605 @@
+use crate::config::SmtpSection;
+use anyhow::Result;
+use common::{AppConfig, NotifySection};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+// [notify] targets of an app get told about deploys, crash loops and health
+// changes. A target is a URL with an optional kind in front:
+//   https://...          JSON webhook, the whole event
+//   slack:https://...    Slack (and Mattermost) incoming webhook
+//   discord:https://...  Discord webhook
+//   matrix:https://.../rooms/<room>/send/m.room.message?access_token=...
+//   ntfy:https://ntfy.sh/<topic>
+//   mailto:ops@example.com, via [smtp] in flared.toml
+
+const DEFAULT_RETRIES: u32 = 3;
+const FIRST_RETRY: Duration = Duration::from_secs(2);
+const SEND_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct Event {
+    // deploy_success, deploy_failed, crash_loop, health
+    pub kind: &'static str,
+    // app dir name
+    pub app: String,
+    pub name: String,
+    pub version: String,
+    pub commit: Option<String>,
+    pub device: String,
+    pub message: String,
+    pub timestamp: i64,
+}
+
+impl Event {
+    fn text(&self, notify: &NotifySection) -> String {
+        let template = notify
+            .templates
+            .get(self.kind)
+            .map(String::as_str)
+            .unwrap_or(match self.kind {
+                "deploy_success" => "{app} {version} deployed on {device}",
+                "deploy_failed" => "{app} failed to deploy on {device}: {message}",
+                "crash_loop" => "{app} keeps crashing on {device}: {message}",
+                _ => "{app} on {device} is {message}",
+            });
+        template
+            .replace("{app}", &self.name)
+            .replace("{version}", &self.version)
+            .replace("{commit}", self.commit.as_deref().unwrap_or(""))
+            .replace("{device}", &self.device)
+            .replace("{event}", self.kind)
+            .replace("{message}", &self.message)
+    }
+
+    // failures get louder where the target can tell
+    fn urgent(&self) -> bool {
+        self.kind != "deploy_success" && !self.message.starts_with("healthy")
+    }
+}
+
+fn targets<'n>(notify: &'n NotifySection, kind: &str) -> &'n [String] {
+    let fail = notify.on_fail.as_deref();
+    let targets = match kind {
+        "deploy_success" => notify.on_success.as_deref(),
+        "crash_loop" => notify.on_crash.as_deref().or(fail),
+        "health" => notify.on_health.as_deref().or(fail),
+        _ => fail,
+    };
+    targets.unwrap_or_default()
+}
+
+// Tells the [notify] targets of the app in `dir` about `kind`. Sending
+// happens in the background, with retries; failures are only logged.
+pub fn send(dir: &Path, kind: &'static str, message: impl Into<String>) {
+    let dir = dir.to_path_buf();
+    let Ok(config) = common::load_app_config(&dir) else {
+        return;
+    };
+    let Some(notify) = config.notify.as_ref() else {
+        return;
+    };
+    if targets(notify, kind).is_empty() {
+        return;
+    }
+
+    let event = event(&dir, &config, kind, message.into());
+    let text = event.text(notify);
+    let retries = notify.retries.unwrap_or(DEFAULT_RETRIES).max(1);
+    let event = Arc::new(event);
+    for target in targets(notify, kind) {
+        let (target, event, text) = (target.clone(), event.clone(), text.clone());
+        tokio::spawn(async move {
+            deliver(&target, &event, &text, retries).await;
+        });
+    }
+}
+
+fn event(dir: &PathBuf, config: &AppConfig, kind: &'static str, message: String) -> Event {
+    let state = common::load_state(dir).ok().flatten();
+    Event {
+        kind,
+        app: dir
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        name: config.app.name.clone(),
+        version: config.app.version.clone(),
+        commit: state.and_then(|s| s.commit),
+        device: crate::sysinfo::hostname(),
+        message,
+        timestamp: chrono::Utc::now().timestamp(),
+    }
+}
+
+// what logs show of a target: its kind and host
+fn describe(target: &str) -> String {
+    if target.starts_with("mailto:") {
+        return target.to_string();
+    }
+    let kind = match target.split_once(':') {
+        Some((kind, rest)) if !rest.starts_with("//") => kind,
+        _ => "webhook",
+    };
+    let url = target.strip_prefix(&format!("{}:", kind)).unwrap_or(target);
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(String::from))
+        .unwrap_or_default();
+    format!("{} {}", kind, host)
+}
+
+async fn deliver(target: &str, event: &Event, text: &str, retries: u32) {
+    let mut wait = FIRST_RETRY;
+    let target_name = describe(target);
+    for attempt in 1..=retries {
+        let result = tokio::time::timeout(SEND_TIMEOUT, post(target, event, text))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+        match result {
+            Ok(()) => {
+                info!("Notified {} of {} ({})", target_name, event.kind, event.app);
+                return;
+            }
+            Err(e) if attempt < retries => {
+                warn!(
+                    "Can't notify {} ({}/{}): {}, retrying in {:?}",
+                    target_name, attempt, retries, e, wait
+                );
+                tokio::time::sleep(wait).await;
+                wait *= 2;
+            }
+            Err(e) => warn!(
+                "Giving up notifying {} of {}: {}",
+                target_name, event.kind, e
+            ),
+        }
+    }
+}
+
+async fn post(target: &str, event: &Event, text: &str) -> Result<()> {
+    let (kind, url) = match target.split_once(':') {
+        Some((kind @ ("slack" | "discord" | "matrix" | "ntfy" | "webhook"), url)) => (kind, url),
+        Some(("mailto", to)) => return mail(&crate::config::get().smtp, to, event, text).await,
+        _ => ("webhook", target),
+    };
+
+    let client = reqwest::Client::new();
+    let request = match kind {
+        "slack" => client.post(url).json(&json!({ "text": text })),
+        "discord" => client.post(url).json(&json!({ "content": text })),
+        "matrix" => {
+            // the send endpoint wants a transaction id at the end
+            let mut url = reqwest::Url::parse(url)?;
+            let txn = format!(
+                "flare-{}",
+                chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0)
+            );
+            url.path_segments_mut()
+                .map_err(|_| anyhow::anyhow!("Bad matrix URL"))?
+                .push(&txn);
+            client
+                .put(url)
+                .json(&json!({ "msgtype": "m.text", "body": text }))
+        }
+        "ntfy" => client
+            .post(url)
+            .header("Title", format!("{}: {}", event.name, event.kind))
+            .header("Priority", if event.urgent() { "high" } else { "default" })
+            .header("Tags", if event.urgent() { "warning" } else { "rocket" })
+            .body(text.to_string()),
+        _ => client.post(url).json(&json!({
+            "event": event.kind,
+            "app": event.app,
+            "name": event.name,
+            "version": event.version,
+            "commit": event.commit,
+            "device": event.device,
+            "message": event.message,
+            "timestamp": event.timestamp,
+            "text": text,
+        })),
+    };
+
+    // webhook URLs are secrets, keep them out of the logs
+    let resp = request.send().await.map_err(|e| e.without_url())?;
+    if !resp.status().is_success() {
+        anyhow::bail!("HTTP {}", resp.status());
+    }
+    Ok(())
+}
+
+async fn mail(smtp: &SmtpSection, to: &str, event: &Event, text: &str) -> Result<()> {
+    let host = smtp
+        .host
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("no [smtp] host in flared.toml"))?;
+    let port = smtp.port.unwrap_or(match smtp.tls.as_str() {
+        "tls" => 465,
+        "none" => 25,
+        _ => 587,
+    });
+    let from = smtp
+        .from
+        .clone()
+        .unwrap_or_else(|| format!("flare@{}", event.device));
+
+    // one address, mailto: extras like ?cc= are dropped; addresses go into
+    // SMTP commands and headers, a line break would start a new one
+    let to = to.split('?').next().unwrap_or_default().trim();
+    if to.is_empty() || [to, from.as_str()].iter().any(|a| a.contains(['\r', '\n'])) {
+        anyhow::bail!("Bad mail address: {:?}", to);
+    }
+    let subject = format!("[flare] {}: {}", event.name, event.kind).replace(['\r', '\n'], " ");
+
+    let message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+        from,
+        to,
+        subject,
+        chrono::Utc::now().to_rfc2822(),
+        text
+    );
+
+    let tcp = TcpStream::connect((host, port)).await?;
+    match smtp.tls.as_str() {
+        "tls" => {
+            let mut session = Smtp::new(tls(host, tcp).await?);
+            session.expect(220).await?;
+            session.deliver(smtp, &from, to, &message).await
+        }
+        "starttls" => {
+            let mut session = Smtp::new(tcp);
+            session.expect(220).await?;
+            session.ehlo().await?;
+            session.command("STARTTLS", 220).await?;
+            let mut session = Smtp::new(tls(host, session.into_inner()).await?);
+            session.deliver(smtp, &from, to, &message).await
+        }
+        _ => {
+            let mut session = Smtp::new(tcp);
+            session.expect(220).await?;
+            session.deliver(smtp, &from, to, &message).await
+        }
+    }
+}
+
+async fn tls(host: &str, tcp: TcpStream) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = rustls::pki_types::ServerName::try_from(host.to_string())?;
+    Ok(tokio_rustls::TlsConnector::from(Arc::new(config))
+        .connect(name, tcp)
+        .await?)
+}
+
+// just enough SMTP to hand one message to a relay
+struct Smtp<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Smtp<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    // a reply may span "250-..." lines, the last one is "250 ..."
+    async fn expect(&mut self, code: u16) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                anyhow::bail!("SMTP server closed the connection");
+            }
+            let got: u16 = line.get(..3).and_then(|c| c.parse().ok()).unwrap_or(0);
+            if got != code {
+                anyhow::bail!("SMTP: {}", line.trim());
+            }
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn command(&mut self, line: &str, code: u16) -> Result<()> {
+        self.stream
+            .get_mut()
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .await?;
+        self.expect(code).await
+    }
+
+    async fn ehlo(&mut self) -> Result<()> {
+        self.command(&format!("EHLO {}", crate::sysinfo::hostname()), 250)
+            .await
+    }
+
+    async fn deliver(
+        &mut self,
+        smtp: &SmtpSection,
+        from: &str,
+        to: &str,
+        message: &str,
+    ) -> Result<()> {
+        use base64::Engine;
+
+        self.ehlo().await?;
+        if let (Some(user), Some(pass)) = (&smtp.username, &smtp.password) {
+            let auth =
+                base64::engine::general_purpose::STANDARD.encode(format!("\0{}\0{}", user, pass));
+            self.command(&format!("AUTH PLAIN {}", auth), 235).await?;
+        }
+        self.command(&format!("MAIL FROM:<{}>", from), 250).await?;
+        self.command(&format!("RCPT TO:<{}>", to), 250).await?;
+        self.command("DATA", 354).await?;
+
+        // lines starting with a dot get another one
+        let body: String = message
+            .lines()
+            .map(|l| {
+                if l.starts_with('.') {
+                    format!(".{}\r\n", l)
+                } else {
+                    format!("{}\r\n", l)
+                }
+            })
+            .collect();
+        self.command(&format!("{}.", body), 250).await?;
+        // the mail is accepted at this point, a retry would send it twice
+        let _ = self.command("QUIT", 221).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Server;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    fn event(kind: &'static str, message: &str) -> Event {
+        Event {
+            kind,
+            app: "x_app".into(),
+            name: "app".into(),
+            version: "1.2.0".into(),
+            commit: Some("abc123".into()),
+            device: "pi".into(),
+            message: message.into(),
+            timestamp: 1700000000,
+        }
+    }
+
+    fn notify() -> NotifySection {
+        NotifySection {
+            on_success: Some(vec!["ntfy:https://ntfy.sh/ok".into()]),
+            on_fail: Some(vec!["mailto:ops@example.com".into()]),
+            on_crash: None,
+            on_health: Some(vec![]),
+            templates: HashMap::from([("crash_loop".into(), "{app}@{commit}: {message}".into())]),
+            retries: None,
+        }
+    }
+
+    #[test]
+    fn picks_targets_and_text() {
+        let notify = notify();
+        assert_eq!(
+            targets(&notify, "deploy_success"),
+            ["ntfy:https://ntfy.sh/ok"]
+        );
+        assert_eq!(targets(&notify, "crash_loop"), ["mailto:ops@example.com"]);
+        assert!(targets(&notify, "health").is_empty());
+
+        let text = event("deploy_success", "").text(&notify);
+        assert_eq!(text, "app 1.2.0 deployed on pi");
+        let text = event("crash_loop", "exited 3 times").text(&notify);
+        assert_eq!(text, "app@abc123: exited 3 times");
+    }
+
+    #[test]
+    fn describe_hides_urls() {
+        assert_eq!(
+            describe("slack:https://hooks.slack.com/services/T0/B0/secret"),
+            "slack hooks.slack.com"
+        );
+        assert_eq!(
+            describe("https://example.com/hook?key=secret"),
+            "webhook example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn webhook_gets_the_event() {
+        let server = Server::start(vec![]).await;
+        let target = format!("{}/hook", server.url);
+        post(&target, &event("deploy_failed", "build failed"), "text")
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].target, "/hook");
+        let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "event": "deploy_failed",
+                "app": "x_app",
+                "name": "app",
+                "version": "1.2.0",
+                "commit": "abc123",
+                "device": "pi",
+                "message": "build failed",
+                "timestamp": 1700000000,
+                "text": "text",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn chat_webhooks_get_the_text() {
+        let server = Server::start(vec![]).await;
+        let event = event("deploy_success", "");
+        post(&format!("slack:{}/s", server.url), &event, "hi")
+            .await
+            .unwrap();
+        post(&format!("discord:{}/d", server.url), &event, "hi")
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        let json =
+            |i: usize| serde_json::from_slice::<serde_json::Value>(&requests[i].body).unwrap();
+        assert_eq!(json(0), json!({ "text": "hi" }));
+        assert_eq!(json(1), json!({ "content": "hi" }));
+    }
+
+    #[tokio::test]
+    async fn ntfy_gets_headers() {
+        let server = Server::start(vec![]).await;
+        let target = format!("ntfy:{}/topic", server.url);
+        post(
+            &target,
+            &event("crash_loop", "exited"),
+            "app keeps crashing",
+        )
+        .await
+        .unwrap();
+        post(&target, &event("health", "healthy again"), "app is healthy")
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].target, "/topic");
+        assert_eq!(requests[0].header("title"), Some("app: crash_loop"));
+        assert_eq!(requests[0].header("priority"), Some("high"));
+        assert_eq!(requests[0].header("tags"), Some("warning"));
+        assert_eq!(requests[0].text(), "app keeps crashing");
+        assert_eq!(requests[1].header("priority"), Some("default"));
+        assert_eq!(requests[1].header("tags"), Some("rocket"));
+    }
+
+    #[tokio::test]
+    async fn failing_webhook_is_an_error() {
+        let server = Server::start(vec![(500, "")]).await;
+        let result = post(&server.url, &event("deploy_success", ""), "hi").await;
+        assert!(result.unwrap_err().to_string().contains("500"));
+    }
+
+    // An SMTP relay that accepts everything, keeps what the client said and
+    // drops the connection instead of answering QUIT.
+    async fn relay() -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let seen = lines.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let mut data = false;
+                let _ = stream.get_mut().write_all(b"220 relay\r\n").await;
+                loop {
+                    let mut line = String::new();
+                    if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        break;
+                    }
+                    let line = line.trim_end_matches("\r\n").to_string();
+                    seen.lock().unwrap().push(line.clone());
+                    let reply: &[u8] = if data {
+                        if line != "." {
+                            continue;
+                        }
+                        data = false;
+                        b"250 queued\r\n"
+                    } else if line.starts_with("EHLO") {
+                        b"250-relay\r\n250 AUTH PLAIN\r\n"
+                    } else if line.starts_with("AUTH") {
+                        b"235 ok\r\n"
+                    } else if line == "DATA" {
+                        data = true;
+                        b"354 go on\r\n"
+                    } else if line == "QUIT" {
+                        break;
+                    } else {
+                        b"250 ok\r\n"
+                    };
+                    let _ = stream.get_mut().write_all(reply).await;
+                }
+            }
+        });
+        (port, lines)
+    }
+
+    fn smtp(port: u16) -> SmtpSection {
+        SmtpSection {
+            host: Some("127.0.0.1".into()),
+            port: Some(port),
+            tls: "none".into(),
+            username: Some("user".into()),
+            password: Some("pass".into()),
+            from: Some("flare@example.com".into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn mails_through_the_relay() {
+        let (port, lines) = relay().await;
+        mail(
+            &smtp(port),
+            "ops@example.com?cc=boss@example.com",
+            &event("deploy_failed", "build failed"),
+            "app failed\n.hidden",
+        )
+        .await
+        .unwrap();
+
+        let lines = lines.lock().unwrap().clone();
+        assert!(lines[0].starts_with("EHLO "));
+        // "\0user\0pass"
+        assert_eq!(lines[1], "AUTH PLAIN AHVzZXIAcGFzcw==");
+        assert_eq!(lines[2], "MAIL FROM:<flare@example.com>");
+        assert_eq!(lines[3], "RCPT TO:<ops@example.com>");
+        assert_eq!(lines[4], "DATA");
+        assert!(lines.contains(&"To: ops@example.com".to_string()));
+        assert!(lines.contains(&"Subject: [flare] app: deploy_failed".to_string()));
+        assert!(lines.contains(&"..hidden".to_string()));
+        assert_eq!(lines[lines.len() - 2..], [".", "QUIT"]);
+    }
+
+    #[tokio::test]
+    async fn refuses_line_breaks_in_addresses() {
+        let (port, lines) = relay().await;
+        let result = mail(
+            &smtp(port),
+            "ops@example.com\r\nRCPT TO:<evil@example.com>",
+            &event("deploy_failed", ""),
+            "",
+        )
+        .await;
+        assert!(result.is_err());
+
+        let mut config = smtp(port);
+        config.from = Some("flare@example.com\nBcc: evil@example.com".into());
+        let result = mail(&config, "ops@example.com", &event("deploy_failed", ""), "").await;
+        assert!(result.is_err());
+        assert!(lines.lock().unwrap().is_empty());
+    }
+}
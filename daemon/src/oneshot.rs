@@ -0,0 +1,86 @@
+use anyhow::Result;
+use common::AppConfig;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+// between SIGTERM and SIGKILL to a timed out group
+const KILL_GRACE: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// "90", "90s", "10m", "1h"
+pub fn parse(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+
+    let secs: u64 = num
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Bad timeout: {}", s))?;
+    let mult = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => anyhow::bail!("Bad timeout: {}", s),
+    };
+    secs.checked_mul(mult)
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow::anyhow!("Bad timeout: {}", s))
+}
+
+// a phase's own timeout, else [resource_limits] timeout, else none
+pub fn timeout(phase: Option<&str>, config: &AppConfig) -> Result<Option<Duration>> {
+    let fallback = config
+        .resource_limits
+        .as_ref()
+        .and_then(|r| r.timeout.as_deref());
+    phase.or(fallback).map(parse).transpose()
+}
+
+// Runs `cmd` to completion in its own process group. Past `limit` the
+// whole group gets SIGTERM, then SIGKILL, so nothing it forked lingers.
+pub fn run(cmd: &mut Command, phase: &str, limit: Option<Duration>) -> Result<ExitStatus> {
+    cmd.process_group(0);
+    let mut child = cmd.spawn()?;
+    let Some(limit) = limit else {
+        return Ok(child.wait()?);
+    };
+
+    let deadline = Instant::now() + limit;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    let group = child.id() as i32;
+    unsafe { libc::kill(-group, libc::SIGTERM) };
+    let deadline = Instant::now() + KILL_GRACE;
+    while Instant::now() < deadline && child.try_wait()?.is_none() {
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    // the leader may be gone while the rest of the group isn't
+    unsafe { libc::kill(-group, libc::SIGKILL) };
+    let _ = child.wait();
+
+    anyhow::bail!("{} timed out after {:?}", phase, limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts() {
+        assert_eq!(parse("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse(" 10 m ").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse("1h").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse("0").unwrap(), Duration::ZERO);
+        let huge = format!("{}h", u64::MAX / 60);
+        for bad in ["", "s", "1.5m", "-1", "10d", "10ms", "1h30m", huge.as_str()] {
+            assert!(parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
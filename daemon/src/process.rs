@@ -0,0 +1,139 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::time::Duration;
+use tracing::warn;
+
+// Apps are spawned as leaders of their own process group, so a signal to
+// the group reaches everything they forked, not just the `sh -c` on top.
+
+// signal to the app's group; apps started before they got one only have the pid
+pub fn signal(pid: u32, sig: i32) -> Result<()> {
+    let pid = pid as i32;
+    unsafe {
+        if libc::kill(-pid, sig) == 0 || libc::kill(pid, sig) == 0 {
+            return Ok(());
+        }
+    }
+    Err(std::io::Error::last_os_error().into())
+}
+
+// SIGTERM now, SIGKILL for whatever is left after `grace`
+pub fn stop(pid: u32, grace: Duration) {
+    if let Err(e) = signal(pid, libc::SIGTERM) {
+        warn!("Can't stop PID {}: {}", pid, e);
+        return;
+    }
+    tokio::spawn(async move {
+        let deadline = tokio::time::Instant::now() + grace;
+        while alive(pid) && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        if alive(pid) {
+            warn!("PID {} ignored SIGTERM, killing", pid);
+            let _ = signal(pid, libc::SIGKILL);
+        }
+    });
+}
+
+// stop(), then wait for the process to be gone, e.g. to have its port back
+pub async fn stop_wait(pid: u32, grace: Duration) {
+    stop(pid, grace);
+    // the SIGKILL from stop() lands at `grace`
+    let deadline = tokio::time::Instant::now() + grace + Duration::from_secs(2);
+    while alive(pid) && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+// the same after stop(), for callers that may block
+pub fn wait_gone(pid: u32, grace: Duration) {
+    let deadline = std::time::Instant::now() + grace + Duration::from_secs(2);
+    while alive(pid) && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+// Running, not a zombie. Our own exited children get reaped on the way,
+// nothing else waits for them.
+pub fn alive(pid: u32) -> bool {
+    unsafe {
+        let pid = pid as i32;
+        if libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG) == pid {
+            return false;
+        }
+    }
+    match stat(pid) {
+        Ok(fields) => !matches!(fields[0].as_str(), "Z" | "X" | "x"),
+        Err(_) => false,
+    }
+}
+
+// /proc/<pid>/stat from field 3 (state) on, so stat field N is index N - 3
+pub fn stat(pid: u32) -> Result<Vec<String>> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // comm may contain spaces and parens, fields start after the last ')'
+    let rest = stat
+        .rsplit_once(')')
+        .map(|(_, r)| r)
+        .ok_or_else(|| anyhow::anyhow!("Bad /proc/{}/stat", pid))?;
+    let fields: Vec<String> = rest.split_whitespace().map(String::from).collect();
+    if fields.len() < 22 {
+        anyhow::bail!("Bad /proc/{}/stat", pid);
+    }
+    Ok(fields)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub pid: u32,
+    // R, S, D, ...
+    pub state: String,
+    // user + system
+    pub cpu_seconds: f64,
+    pub rss_bytes: u64,
+    pub threads: u64,
+    // None when /proc/<pid>/fd isn't ours to read
+    pub open_fds: Option<u64>,
+    // unix time
+    pub started: i64,
+}
+
+pub fn stats(pid: u32) -> Result<Stats> {
+    let fields = stat(pid)?;
+    let num = |i: usize| fields[i].parse::<u64>().unwrap_or(0);
+    let (ticks, page) = unsafe {
+        (
+            libc::sysconf(libc::_SC_CLK_TCK).max(1) as u64,
+            libc::sysconf(libc::_SC_PAGESIZE).max(1) as u64,
+        )
+    };
+
+    let open_fds = std::fs::read_dir(format!("/proc/{}/fd", pid))
+        .ok()
+        .map(|d| d.count() as u64);
+
+    Ok(Stats {
+        pid,
+        state: fields[0].clone(),
+        // utime, stime (14, 15)
+        cpu_seconds: (num(11) + num(12)) as f64 / ticks as f64,
+        // rss in pages (24)
+        rss_bytes: num(21) * page,
+        // num_threads (20)
+        threads: num(17),
+        open_fds,
+        // starttime (22), ticks after boot
+        started: boot_time() + (num(19) / ticks) as i64,
+    })
+}
+
+fn boot_time() -> i64 {
+    std::fs::read_to_string("/proc/stat")
+        .ok()
+        .and_then(|s| {
+            s.lines()
+                .find_map(|l| l.strip_prefix("btime "))
+                .and_then(|t| t.trim().parse().ok())
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+// Apps with [metrics] pushgateway get their series pushed there, for devices
+// nothing can scrape. Each app is its own group, job="flare" with the device
+// as instance, and every push replaces the last one. While the gateway is
+// unreachable pushes back off; counters keep counting meanwhile, so the next
+// push that gets through carries everything.
+
+const DEFAULT_INTERVAL: u64 = 60;
+// how often configs are looked at, also the shortest interval
+const TICK: Duration = Duration::from_secs(5);
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+struct Target {
+    url: String,
+    interval: Duration,
+    next: Instant,
+    // wait after the next failure
+    backoff: Duration,
+    failures: u32,
+}
+
+pub async fn run() {
+    let client = match reqwest::Client::builder().timeout(PUSH_TIMEOUT).build() {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Not pushing metrics: {}", e);
+            return;
+        }
+    };
+    let mut instance = crate::sysinfo::hostname();
+    if instance.is_empty() {
+        instance = "unknown".into();
+    }
+    let mut targets: HashMap<String, Target> = HashMap::new();
+
+    loop {
+        tokio::time::sleep(TICK).await;
+        let configured = tokio::task::spawn_blocking(configured)
+            .await
+            .unwrap_or_default();
+        targets.retain(|app, _| configured.contains_key(app));
+
+        for (app, (url, interval)) in configured {
+            let now = Instant::now();
+            let target = targets
+                .entry(app.clone())
+                .or_insert_with(|| Target::new(url.clone(), interval, now));
+            target.url = url;
+            target.interval = interval;
+            if now < target.next {
+                continue;
+            }
+
+            let name = app.clone();
+            let body = tokio::task::spawn_blocking(move || crate::metrics::render_app(&name))
+                .await
+                .unwrap_or_default();
+            target.attempt(&client, &instance, &app, body, now).await;
+        }
+    }
+}
+
+impl Target {
+    fn new(url: String, interval: Duration, now: Instant) -> Self {
+        Self {
+            url,
+            interval,
+            next: now,
+            backoff: TICK,
+            failures: 0,
+        }
+    }
+
+    // one push, and when to try the next
+    async fn attempt(
+        &mut self,
+        client: &reqwest::Client,
+        instance: &str,
+        app: &str,
+        body: String,
+        now: Instant,
+    ) {
+        match push(client, &self.url, instance, app, body).await {
+            Ok(()) => {
+                if self.failures > 0 {
+                    info!(
+                        "Pushed metrics of {} after {} failed attempt(s)",
+                        app, self.failures
+                    );
+                }
+                self.failures = 0;
+                self.backoff = TICK;
+                self.next = now + self.interval;
+            }
+            Err(e) => {
+                self.failures += 1;
+                warn!(
+                    "Can't push metrics of {} to {}: {}, retrying in {:?}",
+                    app, self.url, e, self.backoff
+                );
+                self.next = now + self.backoff;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+// app dir name -> (pushgateway, interval)
+fn configured() -> HashMap<String, (String, Duration)> {
+    let mut apps = HashMap::new();
+    let Ok(entries) = std::fs::read_dir(crate::config::get().apps_dir()) else {
+        return apps;
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let Ok(config) = common::load_app_config(&entry.path()) else {
+            continue;
+        };
+        let Some(metrics) = config.metrics else {
+            continue;
+        };
+        let Some(url) = metrics.pushgateway else {
+            continue;
+        };
+        let interval = Duration::from_secs(metrics.interval.unwrap_or(DEFAULT_INTERVAL)).max(TICK);
+        apps.insert(
+            entry.file_name().to_string_lossy().into_owned(),
+            (url, interval),
+        );
+    }
+    apps
+}
+
+async fn push(
+    client: &reqwest::Client,
+    base: &str,
+    instance: &str,
+    app: &str,
+    body: String,
+) -> anyhow::Result<()> {
+    let url = format!(
+        "{}/metrics/job/flare/instance/{}/app/{}",
+        base.trim_end_matches('/'),
+        instance,
+        app
+    );
+    let resp = client
+        .put(&url)
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(body)
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        anyhow::bail!("HTTP {}", resp.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Server;
+
+    #[tokio::test]
+    async fn pushes_to_the_app_group() {
+        let server = Server::start(vec![]).await;
+        let client = reqwest::Client::new();
+        push(
+            &client,
+            &format!("{}/", server.url),
+            "pi",
+            "x_app",
+            "a 1\n".into(),
+        )
+        .await
+        .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "PUT");
+        assert_eq!(
+            requests[0].target,
+            "/metrics/job/flare/instance/pi/app/x_app"
+        );
+        assert_eq!(
+            requests[0].header("content-type"),
+            Some("text/plain; version=0.0.4")
+        );
+        assert_eq!(requests[0].text(), "a 1\n");
+    }
+
+    #[tokio::test]
+    async fn backs_off_while_failing() {
+        let server = Server::start(vec![(500, ""), (503, ""), (500, "")]).await;
+        let client = reqwest::Client::new();
+        let now = Instant::now();
+        let mut target = Target::new(server.url.clone(), Duration::from_secs(60), now);
+
+        let mut waits = Vec::new();
+        for _ in 0..3 {
+            target
+                .attempt(&client, "pi", "x_app", String::new(), now)
+                .await;
+            waits.push(target.next - now);
+        }
+        assert_eq!(target.failures, 3);
+        assert_eq!(waits, [TICK, TICK * 2, TICK * 4]);
+
+        // the gateway is back: normal interval, backoff reset
+        target
+            .attempt(&client, "pi", "x_app", String::new(), now)
+            .await;
+        assert_eq!(target.failures, 0);
+        assert_eq!(target.backoff, TICK);
+        assert_eq!(target.next - now, Duration::from_secs(60));
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn backoff_is_capped() {
+        let server = Server::start(vec![(500, ""); 10]).await;
+        let client = reqwest::Client::new();
+        let now = Instant::now();
+        let mut target = Target::new(server.url.clone(), Duration::from_secs(60), now);
+        for _ in 0..10 {
+            target
+                .attempt(&client, "pi", "x_app", String::new(), now)
+                .await;
+        }
+        assert_eq!(target.backoff, MAX_BACKOFF);
+        assert_eq!(target.next - now, MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn next_push_carries_what_counted_meanwhile() {
+        let server = Server::start(vec![(500, "")]).await;
+        let client = reqwest::Client::new();
+        let now = Instant::now();
+        let mut target = Target::new(server.url.clone(), Duration::from_secs(60), now);
+        crate::testing::config();
+
+        crate::metrics::deploy("x_buffered", true, Duration::from_secs(1));
+        let body = crate::metrics::render_app("x_buffered");
+        target.attempt(&client, "pi", "x_buffered", body, now).await;
+        crate::metrics::deploy("x_buffered", true, Duration::from_secs(1));
+        let body = crate::metrics::render_app("x_buffered");
+        target.attempt(&client, "pi", "x_buffered", body, now).await;
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(target.failures, 0);
+        assert!(
+            requests[1]
+                .text()
+                .contains("flare_deploys_total{app=\"x_buffered\",result=\"success\"} 2"),
+            "{}",
+            requests[1].text()
+        );
+    }
+}
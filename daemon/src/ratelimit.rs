@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+// failed attempts before the peer gets banned
+const MAX_FAILURES: u32 = 5;
+// delay after the first failure, doubles with every next one
+const BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+const BAN_DURATION: Duration = Duration::from_secs(15 * 60);
+// peer's failures are forgotten after this long without new ones
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+// max TLS handshakes in progress at once (all peers)
+pub const MAX_PENDING_HANDSHAKES: usize = 16;
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// argon2 checks running at once (all peers), the rest wait
+const MAX_VERIFICATIONS: usize = 4;
+
+pub type Limiter = Arc<AuthLimiter>;
+
+pub struct AuthLimiter {
+    peers: Mutex<HashMap<IpAddr, Attempts>>,
+    pub verifications: Semaphore,
+}
+
+impl Default for AuthLimiter {
+    fn default() -> Self {
+        Self {
+            peers: Mutex::default(),
+            verifications: Semaphore::new(MAX_VERIFICATIONS),
+        }
+    }
+}
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    // no new attempt is checked before this
+    next_attempt: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl AuthLimiter {
+    pub fn is_banned(&self, peer: IpAddr) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let now = Instant::now();
+
+        peers.retain(|_, a| {
+            a.banned_until.is_some_and(|t| t > now) || now - a.last_failure < FORGET_AFTER
+        });
+
+        peers
+            .get(&peer)
+            .and_then(|a| a.banned_until)
+            .is_some_and(|t| t > now)
+    }
+
+    // Some(wait) while the peer is banned or still in its delay; attempts
+    // then are refused without checking the token
+    pub fn retry_after(&self, peer: IpAddr) -> Option<Duration> {
+        let peers = self.peers.lock().unwrap();
+        let attempts = peers.get(&peer)?;
+        let until = attempts
+            .banned_until
+            .map_or(attempts.next_attempt, |t| t.max(attempts.next_attempt));
+        let now = Instant::now();
+        (until > now).then(|| until - now)
+    }
+
+    // returns how long to hold the peer before answering
+    pub fn record_failure(&self, peer: IpAddr) -> Duration {
+        let mut peers = self.peers.lock().unwrap();
+        let now = Instant::now();
+
+        let attempts = peers.entry(peer).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            next_attempt: now,
+            banned_until: None,
+        });
+
+        attempts.failures += 1;
+        attempts.last_failure = now;
+
+        if attempts.failures >= MAX_FAILURES {
+            warn!(
+                "Banning {} for {}s after {} failed auth attempts",
+                peer,
+                BAN_DURATION.as_secs(),
+                attempts.failures
+            );
+            attempts.failures = 0;
+            attempts.banned_until = Some(now + BAN_DURATION);
+            attempts.next_attempt = now + MAX_DELAY;
+            return MAX_DELAY;
+        }
+
+        let delay = BASE_DELAY
+            .saturating_mul(1 << (attempts.failures - 1))
+            .min(MAX_DELAY);
+        attempts.next_attempt = now + delay;
+        delay
+    }
+
+    pub fn record_success(&self, peer: IpAddr) {
+        self.peers.lock().unwrap().remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn delay_doubles_until_the_ban() {
+        let limiter = AuthLimiter::default();
+        assert_eq!(limiter.retry_after(PEER), None);
+
+        let delays: Vec<_> = (0..MAX_FAILURES - 1)
+            .map(|_| limiter.record_failure(PEER))
+            .collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000].map(Duration::from_millis));
+        assert!(!limiter.is_banned(PEER));
+        let wait = limiter.retry_after(PEER).unwrap();
+        assert!(wait <= delays[3] && wait > delays[2]);
+
+        assert_eq!(limiter.record_failure(PEER), MAX_DELAY);
+        assert!(limiter.is_banned(PEER));
+        // the ban, not the last delay
+        assert!(limiter.retry_after(PEER).unwrap() > MAX_DELAY);
+        assert!(!limiter.is_banned(OTHER));
+        assert_eq!(limiter.retry_after(OTHER), None);
+    }
+
+    #[test]
+    fn success_forgets_failures() {
+        let limiter = AuthLimiter::default();
+        limiter.record_failure(PEER);
+        limiter.record_failure(PEER);
+        limiter.record_success(PEER);
+        assert_eq!(limiter.retry_after(PEER), None);
+        assert_eq!(limiter.record_failure(PEER), BASE_DELAY);
+    }
+}
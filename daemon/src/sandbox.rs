@@ -0,0 +1,632 @@
+use anyhow::Result;
+use common::AppConfig;
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// `flared __sandbox <spec> -- <command>` starts an app in its own namespaces
+pub const ARG: &str = "__sandbox";
+
+// visible read-only inside, when present on the host
+const SYSTEM_DIRS: &[&str] = &[
+    "/usr",
+    "/bin",
+    "/sbin",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/etc",
+    "/run/systemd/resolve",
+];
+const DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom", "tty"];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Spec {
+    // app directory, the only writable host path
+    dir: PathBuf,
+    hostname: String,
+    // own network namespace, reachable through `ports` (host, app)
+    private_network: bool,
+    ports: Vec<(u16, u16)>,
+    seccomp: bool,
+}
+
+// daemon side: the launcher command, the app command goes after it
+pub fn command(config: &AppConfig, dir: &Path) -> Result<Command> {
+    let isolation = config.isolation.as_ref();
+    let private_network = match isolation.and_then(|i| i.network.as_deref()) {
+        None | Some("host") => false,
+        Some("private") => true,
+        Some(other) => anyhow::bail!("Unknown isolation.network: {}", other),
+    };
+
+    let mut ports = Vec::new();
+    for p in isolation.map(|i| i.ports.as_slice()).unwrap_or_default() {
+        ports.push(parse_port(p)?);
+    }
+    // the app's own port, so gateway and health checks keep working
+    if private_network
+        && ports.is_empty()
+        && let Some(port) = config.run.as_ref().and_then(|r| r.port)
+    {
+        ports.push((port, port));
+    }
+
+    let spec = Spec {
+        dir: dir.canonicalize()?,
+        hostname: config.app.name.clone(),
+        private_network,
+        ports,
+        seccomp: isolation.and_then(|i| i.seccomp).unwrap_or(true),
+    };
+
+    let mut cmd = Command::new(std::env::current_exe()?);
+    cmd.arg(ARG).arg(serde_json::to_string(&spec)?).arg("--");
+    Ok(cmd)
+}
+
+// "8080:3000" (host:app) or "3000"
+fn parse_port(s: &str) -> Result<(u16, u16)> {
+    let bad = || anyhow::anyhow!("Bad port forward: {}", s);
+    match s.split_once(':') {
+        Some((host, app)) => Ok((
+            host.trim().parse().map_err(|_| bad())?,
+            app.trim().parse().map_err(|_| bad())?,
+        )),
+        None => {
+            let port = s.trim().parse().map_err(|_| bad())?;
+            Ok((port, port))
+        }
+    }
+}
+
+// Launcher, runs before anything else in flared and stays single-threaded
+// until it forked: unshare() of a user namespace needs that.
+//
+//   flared __sandbox    relays signals to init
+//   ├─ init (pid 1)     own mount tree, reaps
+//   │  └─ app           seccomp, exec
+//   └─ forwarder        host ports into the sandbox network
+pub fn main() -> ! {
+    let code = launch().unwrap_or_else(|e| {
+        eprintln!("flared sandbox: {}", e);
+        127
+    });
+    std::process::exit(code)
+}
+
+fn launch() -> Result<i32> {
+    let mut args = std::env::args().skip(2);
+    let spec: Spec = serde_json::from_str(&args.next().unwrap_or_default())?;
+    if args.next().as_deref() != Some("--") {
+        anyhow::bail!("Usage: flared {} <spec> -- <command>", ARG);
+    }
+    let argv = args.map(CString::new).collect::<Result<Vec<_>, _>>()?;
+    if argv.is_empty() {
+        anyhow::bail!("No command");
+    }
+
+    // bound while we are still in the host network
+    let listeners = spec
+        .ports
+        .iter()
+        .map(|(host, app)| Ok((TcpListener::bind(("0.0.0.0", *host))?, *app)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let mut flags =
+        libc::CLONE_NEWUSER | libc::CLONE_NEWPID | libc::CLONE_NEWIPC | libc::CLONE_NEWUTS;
+    if spec.private_network {
+        flags |= libc::CLONE_NEWNET;
+    }
+    check(unsafe { libc::unshare(flags) }).map_err(|e| {
+        anyhow::anyhow!("Can't create namespaces (user namespaces disabled?): {}", e)
+    })?;
+
+    // same ids inside; capabilities are gone after exec unless that is root
+    std::fs::write("/proc/self/setgroups", "deny")?;
+    std::fs::write("/proc/self/uid_map", format!("{} {} 1", uid, uid))?;
+    std::fs::write("/proc/self/gid_map", format!("{} {} 1", gid, gid))?;
+
+    match check(unsafe { libc::fork() })? {
+        0 => {
+            drop(listeners);
+            let code = init(&spec, &argv).unwrap_or_else(|e| {
+                eprintln!("flared sandbox: {}", e);
+                127
+            });
+            std::process::exit(code)
+        }
+        init => {
+            // no threads here after unshare(CLONE_NEWPID), the forwarder is a process
+            // inside the sandbox pid namespace and goes down with it
+            if !listeners.is_empty() && check(unsafe { libc::fork() })? == 0 {
+                let threads: Vec<_> = listeners
+                    .into_iter()
+                    .map(|(listener, port)| std::thread::spawn(move || forward(listener, port)))
+                    .collect();
+                for t in threads {
+                    let _ = t.join();
+                }
+                std::process::exit(0)
+            }
+            relay_signals(init);
+            Ok(wait_for(init))
+        }
+    }
+}
+
+// pid 1 of the sandbox
+fn init(spec: &Spec, argv: &[CString]) -> Result<i32> {
+    // launcher killed: take the whole sandbox down with it
+    unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+
+    build_root(spec)?;
+    let name = spec.hostname.as_bytes();
+    unsafe { libc::sethostname(name.as_ptr().cast(), name.len()) };
+    if spec.private_network {
+        loopback_up()?;
+    }
+
+    match check(unsafe { libc::fork() })? {
+        0 => {
+            let err = exec(spec, argv);
+            eprintln!("flared sandbox: {}", err);
+            std::process::exit(127)
+        }
+        app => {
+            relay_signals(app);
+            // reaps orphans too, init's job
+            loop {
+                let mut status = 0;
+                let pid = unsafe { libc::waitpid(-1, &mut status, 0) };
+                if pid == app {
+                    return Ok(exit_code(status));
+                }
+                if pid < 0 && std::io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                    return Ok(127);
+                }
+            }
+        }
+    }
+}
+
+// fresh tmpfs root with the system dirs read-only, the app dir writable,
+// own /proc, /tmp and a minimal /dev
+fn build_root(spec: &Spec) -> Result<()> {
+    // own mount namespace, the launcher keeps the host's view
+    check(unsafe { libc::unshare(libc::CLONE_NEWNS) })?;
+    // nothing below leaks to the host
+    mount(None, "/", None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+
+    // scratch root, the host's stays reachable at /oldroot while we build
+    mount(Some("tmpfs"), "/tmp", Some("tmpfs"), 0, Some("mode=0755"))?;
+    std::fs::create_dir("/tmp/newroot")?;
+    std::fs::create_dir("/tmp/oldroot")?;
+    pivot_root("/tmp", "/tmp/oldroot")?;
+    std::env::set_current_dir("/")?;
+
+    let nosuid = libc::MS_NOSUID | libc::MS_NODEV;
+    mount(
+        Some("tmpfs"),
+        "/newroot",
+        Some("tmpfs"),
+        nosuid,
+        Some("mode=0755"),
+    )?;
+    for dir in SYSTEM_DIRS {
+        bind(Path::new(dir), false)?;
+    }
+
+    std::fs::create_dir("/newroot/proc")?;
+    mount(
+        Some("proc"),
+        "/newroot/proc",
+        Some("proc"),
+        nosuid | libc::MS_NOEXEC,
+        None,
+    )?;
+    std::fs::create_dir("/newroot/tmp")?;
+    mount(
+        Some("tmpfs"),
+        "/newroot/tmp",
+        Some("tmpfs"),
+        nosuid,
+        Some("mode=1777"),
+    )?;
+
+    std::fs::create_dir("/newroot/dev")?;
+    mount(
+        Some("tmpfs"),
+        "/newroot/dev",
+        Some("tmpfs"),
+        libc::MS_NOSUID | libc::MS_NOEXEC,
+        Some("mode=0755"),
+    )?;
+    for dev in DEVICES {
+        let src = Path::new("/oldroot/dev").join(dev);
+        if src.exists() {
+            let dst = Path::new("/newroot/dev").join(dev);
+            std::fs::File::create(&dst)?;
+            mount(
+                src.to_str(),
+                dst.to_str().unwrap(),
+                None,
+                libc::MS_BIND,
+                None,
+            )?;
+        }
+    }
+    std::fs::create_dir("/newroot/dev/shm")?;
+    mount(
+        Some("tmpfs"),
+        "/newroot/dev/shm",
+        Some("tmpfs"),
+        nosuid,
+        Some("mode=1777"),
+    )?;
+    for (link, target) in [
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+    ] {
+        std::os::unix::fs::symlink(target, Path::new("/newroot/dev").join(link))?;
+    }
+
+    // last, it may live below one of the mounts above (/tmp)
+    bind(&spec.dir, true)?;
+
+    // only the mounts made above are writable
+    mount(
+        None,
+        "/newroot",
+        None,
+        libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | nosuid,
+        None,
+    )?;
+
+    check(unsafe { libc::umount2(c"/oldroot".as_ptr(), libc::MNT_DETACH) })?;
+    std::env::set_current_dir("/newroot")?;
+    pivot_root(".", ".")?;
+    check(unsafe { libc::umount2(c".".as_ptr(), libc::MNT_DETACH) })?;
+    std::env::set_current_dir(&spec.dir)?;
+    Ok(())
+}
+
+// host `path` at the same place inside
+fn bind(path: &Path, writable: bool) -> Result<()> {
+    let rel = path.strip_prefix("/")?;
+    let src = Path::new("/oldroot").join(rel);
+    let dst = Path::new("/newroot").join(rel);
+
+    let Ok(meta) = std::fs::symlink_metadata(&src) else {
+        // not on this distro
+        return Ok(());
+    };
+    if meta.file_type().is_symlink() {
+        // merged /usr: /bin -> usr/bin
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::os::unix::fs::symlink(std::fs::read_link(&src)?, &dst)?;
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(&dst)?;
+    let dst = dst.to_str().unwrap();
+    mount(src.to_str(), dst, None, libc::MS_BIND | libc::MS_REC, None)?;
+    if !writable {
+        // flags the host mount has are locked in a user namespace, keep them
+        let flags = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | locked_flags(dst);
+        mount(None, dst, None, flags, None)?;
+    }
+    Ok(())
+}
+
+fn locked_flags(path: &str) -> libc::c_ulong {
+    let path = CString::new(path).unwrap();
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut st) } != 0 {
+        return 0;
+    }
+    [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ]
+    .iter()
+    .filter(|(st_flag, _)| st.f_flag & st_flag != 0)
+    .fold(0, |acc, (_, ms_flag)| acc | ms_flag)
+}
+
+fn mount(
+    src: Option<&str>,
+    dst: &str,
+    fstype: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> Result<()> {
+    let cstr = |s: Option<&str>| s.map(|s| CString::new(s).unwrap());
+    let (src_c, fstype_c, data_c) = (cstr(src), cstr(fstype), cstr(data));
+    let dst_c = CString::new(dst)?;
+    let ptr = |s: &Option<CString>| s.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
+
+    let rc = unsafe {
+        libc::mount(
+            ptr(&src_c),
+            dst_c.as_ptr(),
+            ptr(&fstype_c),
+            flags,
+            ptr(&data_c).cast(),
+        )
+    };
+    check(rc).map_err(|e| anyhow::anyhow!("mount {}: {}", dst, e))?;
+    Ok(())
+}
+
+fn pivot_root(new_root: &str, put_old: &str) -> Result<()> {
+    let (new_root, put_old) = (CString::new(new_root)?, CString::new(put_old)?);
+    let rc = unsafe { libc::syscall(libc::SYS_pivot_root, new_root.as_ptr(), put_old.as_ptr()) };
+    check(rc as i32).map_err(|e| anyhow::anyhow!("pivot_root: {}", e))?;
+    Ok(())
+}
+
+// a new network namespace starts with lo down
+fn loopback_up() -> Result<()> {
+    unsafe {
+        let fd = check(libc::socket(
+            libc::AF_INET,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            0,
+        ))?;
+        let mut req: libc::ifreq = std::mem::zeroed();
+        for (i, b) in b"lo".iter().enumerate() {
+            req.ifr_name[i] = *b as libc::c_char;
+        }
+        let result = check(libc::ioctl(fd, libc::SIOCGIFFLAGS as _, &mut req)).and_then(|_| {
+            req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+            check(libc::ioctl(fd, libc::SIOCSIFFLAGS as _, &req))
+        });
+        libc::close(fd);
+        result.map_err(|e| anyhow::anyhow!("Can't bring up lo: {}", e))?;
+    }
+    Ok(())
+}
+
+// only returns on failure
+fn exec(spec: &Spec, argv: &[CString]) -> anyhow::Error {
+    unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    if spec.seccomp
+        && let Err(e) = install_seccomp()
+    {
+        return e;
+    }
+
+    let mut ptrs: Vec<_> = argv.iter().map(|a| a.as_ptr()).collect();
+    ptrs.push(std::ptr::null());
+    unsafe { libc::execvp(ptrs[0], ptrs.as_ptr()) };
+    anyhow::anyhow!("exec {:?}: {}", argv[0], std::io::Error::last_os_error())
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+#[cfg(target_arch = "arm")]
+const AUDIT_ARCH: Option<u32> = Some(0x4000_0028);
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_00F3);
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "arm",
+    target_arch = "riscv64"
+)))]
+const AUDIT_ARCH: Option<u32> = None;
+
+// Default profile: an app has no business changing mounts, namespaces,
+// kernel modules, the clock or the keyring. These fail with EPERM, and so
+// does clone() with CLONE_NEW* flags (see install_seccomp).
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_acct,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_adjtimex,
+    libc::SYS_quotactl,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_userfaultfd,
+    libc::SYS_syslog,
+    libc::SYS_process_vm_writev,
+];
+
+// CLONE_NEWNS, NEWCGROUP, NEWUTS, NEWIPC, NEWUSER, NEWPID, NEWNET
+const CLONE_NEW: u32 = (libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET) as u32;
+
+fn install_seccomp() -> Result<()> {
+    let Some(arch) = AUDIT_ARCH else {
+        eprintln!("flared sandbox: no seccomp filter for this architecture");
+        return Ok(());
+    };
+
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let jeq = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+    let ret = libc::BPF_RET | libc::BPF_K;
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    // seccomp_data: nr at 0, arch at 4
+    let mut filter = vec![
+        stmt(load, 4),
+        jump(jeq, arch, 1, 0),
+        stmt(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(load, 0),
+    ];
+    // x32 syscalls would dodge the numbers below
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        jump(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            0x4000_0000,
+            0,
+            1,
+        ),
+        stmt(ret, deny),
+    ]);
+    for nr in DENIED {
+        filter.push(jump(jeq, *nr as u32, 0, 1));
+        filter.push(stmt(ret, deny));
+    }
+    // clone3 takes its flags in a struct a filter can't read; ENOSYS makes
+    // libc fall back to clone, whose flags (args[0], low half at 16) can be
+    let allow = libc::SECCOMP_RET_ALLOW;
+    filter.extend([
+        jump(jeq, libc::SYS_clone3 as u32, 0, 1),
+        stmt(ret, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        jump(jeq, libc::SYS_clone as u32, 0, 3),
+        stmt(load, 16),
+        jump(
+            libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K,
+            CLONE_NEW,
+            0,
+            1,
+        ),
+        stmt(ret, deny),
+        stmt(ret, allow),
+    ]);
+
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    let rc = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+    check(rc).map_err(|e| anyhow::anyhow!("seccomp: {}", e))?;
+    Ok(())
+}
+
+// the launcher sits in the sandbox network namespace, its listeners in the host's
+fn forward(listener: TcpListener, port: u16) {
+    for client in listener.incoming().flatten() {
+        std::thread::spawn(move || {
+            let Ok(app) = TcpStream::connect(("127.0.0.1", port)) else {
+                return;
+            };
+            let (Ok(mut client_r), Ok(mut app_w)) = (client.try_clone(), app.try_clone()) else {
+                return;
+            };
+            let upstream = std::thread::spawn(move || {
+                let _ = std::io::copy(&mut client_r, &mut app_w);
+                let _ = app_w.shutdown(Shutdown::Write);
+            });
+            let (mut app_r, mut client_w) = (app, client);
+            let _ = std::io::copy(&mut app_r, &mut client_w);
+            let _ = client_w.shutdown(Shutdown::Write);
+            let _ = upstream.join();
+        });
+    }
+}
+
+static RELAY_TO: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn relay(sig: libc::c_int) {
+    let pid = RELAY_TO.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe { libc::kill(pid, sig) };
+    }
+}
+
+// stop/restart signal the launcher, the app is who should get them
+fn relay_signals(to: i32) {
+    RELAY_TO.store(to, Ordering::SeqCst);
+    for sig in [
+        libc::SIGTERM,
+        libc::SIGINT,
+        libc::SIGHUP,
+        libc::SIGQUIT,
+        libc::SIGUSR1,
+        libc::SIGUSR2,
+    ] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = relay as *const () as usize;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigaction(sig, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+// reaps the forwarder as well: a pid namespace only goes away once
+// all of its processes are reaped, init included
+fn wait_for(pid: i32) -> i32 {
+    loop {
+        let mut status = 0;
+        let reaped = unsafe { libc::waitpid(-1, &mut status, 0) };
+        if reaped == pid {
+            return exit_code(status);
+        }
+        if reaped < 0 && std::io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            return 127;
+        }
+    }
+}
+
+fn exit_code(status: i32) -> i32 {
+    if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        libc::WEXITSTATUS(status)
+    }
+}
+
+fn check(rc: i32) -> std::io::Result<i32> {
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(rc)
+}
@@ -1,16 +1,22 @@
 use anyhow::Result;
 use common::{
-    DeployRequest, ManageRequest, ManageResponse, RegisterTokenRequest, recv_json, send_json,
+    Chunk, Envelope, ErrorCode, ErrorResponse, Event, Frame, HelloResponse, MIN_PROTOCOL_VERSION,
+    ManageRequest, ManageResponse, PROTOCOL_VERSION, PairingRequest, PairingResponse, PendingToken,
+    Request, Response, StatusResponse, SubscribeRequest, TRANSFER_WINDOW, recv_frame, send_json,
 };
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
-use tokio_rustls::server::TlsStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{Mutex, RwLock, Semaphore, broadcast, mpsc, oneshot, watch};
 use tracing::{error, info, warn};
 
+use crate::ratelimit::{AuthLimiter, HANDSHAKE_TIMEOUT, Limiter, MAX_PENDING_HANDSHAKES};
+use crate::shutdown::{Lifecycle, Shutdown};
+
 pub type Routes = Arc<RwLock<GatewayState>>;
 
 #[derive(Default)]
@@ -22,10 +28,13 @@ pub struct GatewayState {
 #[derive(Debug, Serialize, Deserialize, Default)]
 struct TokenStore {
     tokens: Vec<String>,
+    // registered over the network, not usable until approved locally
+    #[serde(default)]
+    pending: Vec<PendingToken>,
 }
 
 fn tokens_path() -> PathBuf {
-    common::flare_dir().join("daemon_tokens.toml")
+    crate::config::get().data_dir.join("daemon_tokens.toml")
 }
 
 fn load_tokens() -> TokenStore {
@@ -47,121 +56,525 @@ fn save_tokens(store: &TokenStore) -> Result<()> {
 
 pub type HealthPids = Arc<RwLock<HashMap<String, Option<u32>>>>;
 
-pub async fn run(port: u16) -> Result<()> {
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
-    info!("Listening on port {}", port);
+// daemon events fanned out to subscribed sessions
+pub type Events = broadcast::Sender<Event>;
+const EVENTS_BUFFER: usize = 256;
+
+pub async fn run() -> Result<()> {
+    let config = crate::config::get();
+    let listener = crate::upgrade::bind("control", &config.control.addr())?;
+    info!("Listening on {}", config.control.addr());
 
     let routes: Routes = Arc::new(RwLock::new(GatewayState::default()));
     let health_pids: HealthPids = Arc::new(RwLock::new(HashMap::new()));
+    let limiter: Limiter = Arc::new(AuthLimiter::default());
+    let handshakes = Arc::new(Semaphore::new(MAX_PENDING_HANDSHAKES));
+
+    // apps left running by the previous daemon
+    crate::adopt::adopt_all(&routes, &health_pids).await;
 
     // start gateway
     let routes_clone = routes.clone();
     let health_pids_clone = health_pids.clone();
 
-    tokio::spawn(async move {
-        if let Err(e) = crate::gateway::run(routes_clone).await {
-            error!("Gateway error: {}", e);
+    if config.gateway.enabled {
+        match crate::upgrade::bind("gateway", &config.gateway.addr()) {
+            Ok(listener) => {
+                tokio::spawn(async move {
+                    if let Err(e) = crate::gateway::run(routes_clone, listener).await {
+                        error!("Gateway error: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Gateway error: {}", e),
         }
-    });
+    }
 
     // Start health server
-    tokio::spawn(async move {
-        if let Err(e) = crate::health_server::run(health_pids_clone).await {
-            error!("Health server error: {}", e);
+    if config.health.enabled {
+        match crate::upgrade::bind("health", &config.health.addr()) {
+            Ok(listener) => {
+                tokio::spawn(async move {
+                    if let Err(e) = crate::health_server::run(health_pids_clone, listener).await {
+                        error!("Health server error: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Health server error: {}", e),
         }
-    });
+    }
 
     // start discovery
-    tokio::spawn(async move {
-        if let Err(e) = crate::discovery::run(7001).await {
-            error!("Discovery error: {}", e);
-        }
-    });
+    if config.discovery.enabled {
+        tokio::spawn(async move {
+            if let Err(e) = crate::discovery::run().await {
+                error!("Discovery error: {}", e);
+            }
+        });
+    }
+
+    // [metrics] pushgateway of any app
+    tokio::spawn(crate::pushgateway::run());
+    tokio::spawn(crate::storage::watch_quotas());
+
+    let (events, _) = broadcast::channel(EVENTS_BUFFER);
+    let shared = Shared {
+        routes,
+        health_pids,
+        limiter,
+        events,
+        lifecycle: Arc::new(Shutdown::default()),
+    };
+
+    if config.local.enabled {
+        let local_shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::local::run(local_shared).await {
+                error!("Local socket error: {}", e);
+            }
+        });
+    }
+
+    // up and listening, a fresh upgrade is here to stay
+    crate::upgrade::confirm();
+
+    let shutdown = crate::shutdown::signal_received();
+    tokio::pin!(shutdown);
 
     loop {
-        let (tcp, addr) = listener.accept().await?;
-        info!("Connection from {}", addr);
+        let (tcp, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            signal = &mut shutdown => {
+                signal?;
+                break;
+            }
+        };
 
-        let socket = match crate::tls::accept(tcp).await {
-            Ok(s) => s,
-            Err(e) => {
-                error!("TLS handshake failed: {}", e);
+        // drop banned peers before spending CPU on a handshake
+        if shared.limiter.is_banned(addr.ip()) {
+            warn!("Rejected banned peer {}", addr);
+            continue;
+        }
+
+        let permit = match handshakes.clone().try_acquire_owned() {
+            Ok(p) => p,
+            Err(_) => {
+                warn!("Too many pending handshakes, dropping {}", addr);
                 continue;
             }
         };
 
-        let routes = routes.clone();
-        let health_pids = health_pids.clone();
+        info!("Connection from {}", addr);
+
+        let shared = shared.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle(socket, routes, health_pids).await {
+            let socket =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, crate::tls::accept(tcp)).await {
+                    Ok(Ok(s)) => s,
+                    Ok(Err(e)) => {
+                        error!("TLS handshake failed: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("TLS handshake timed out: {}", addr);
+                        return;
+                    }
+                };
+            drop(permit);
+
+            if let Err(e) = handle(socket, Peer::Remote(addr), shared).await {
                 error!("Handler error: {}", e);
             }
         });
     }
+
+    drop(listener);
+    info!("Shutting down, no longer accepting connections");
+
+    let grace = config.shutdown_timeout();
+    shared.lifecycle.drain(grace).await;
+
+    if config.shutdown.apps == "stop" {
+        crate::shutdown::stop_apps(grace).await;
+    } else {
+        info!("Leaving apps running");
+    }
+
+    if config.local.enabled {
+        let _ = std::fs::remove_file(config.socket_path());
+    }
+    Ok(())
 }
 
-async fn handle(
-    mut socket: TlsStream<TcpStream>,
+// requests this daemon can serve, announced in hello
+const CAPABILITIES: &[&str] = &[
+    "register_token",
+    "deploy",
+    "manage",
+    "subscribe",
+    "cancel",
+    "upload",
+    "download",
+    "transfer_ack",
+    "status",
+    "pairing",
+    "upgrade",
+];
+
+// state shared by all control connections
+#[derive(Clone)]
+pub struct Shared {
     routes: Routes,
     health_pids: HealthPids,
-) -> Result<()> {
-    let msg: serde_json::Value = common::recv_json(&mut socket).await?;
+    limiter: Limiter,
+    events: Events,
+    lifecycle: Lifecycle,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Peer {
+    // TLS over TCP, authenticated by daemon token
+    Remote(SocketAddr),
+    // local admin socket, uid checked by SO_PEERCRED
+    Local(u32),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Remote(addr) => write!(f, "{}", addr),
+            Peer::Local(uid) => write!(f, "local uid {}", uid),
+        }
+    }
+}
+
+pub type Writer = Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>;
+
+pub async fn handle<S>(mut socket: S, peer: Peer, shared: Shared) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    // every connection starts with hello
+    let first = match recv_frame(&mut socket).await? {
+        Frame::Json(msg) => parse_request(msg).1,
+        Frame::Chunk(_) => Err(ErrorResponse::new(
+            ErrorCode::BadRequest,
+            "Chunk before hello",
+        )),
+    };
+    let hello = match first {
+        Ok(Request::Hello(h)) => h,
+        Ok(other) => {
+            let msg = format!("Expected hello, got '{}'", other.kind());
+            let err = ErrorResponse::new(ErrorCode::HandshakeRequired, msg);
+            return send_json(&mut socket, &Response::Error(err)).await;
+        }
+        Err(e) => return send_json(&mut socket, &Response::Error(e)).await,
+    };
+
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.version) {
+        warn!(
+            "{} ({}) speaks protocol v{}, we support v{}-v{}",
+            peer, hello.client, hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        let msg = format!(
+            "Protocol v{} not supported, daemon supports v{}-v{}",
+            hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        let err = ErrorResponse::new(ErrorCode::UnsupportedVersion, msg);
+        return send_json(&mut socket, &Response::Error(err)).await;
+    }
+
+    let resp = Response::Hello(HelloResponse {
+        version: PROTOCOL_VERSION,
+        daemon: format!("flared {}", env!("CARGO_PKG_VERSION")),
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    });
+    send_json(&mut socket, &resp).await?;
+
+    // session: enveloped requests until the client hangs up, each served in its own task
+    let (mut reader, writer) = tokio::io::split(socket);
+    let writer: Writer = Arc::new(Mutex::new(Box::new(writer)));
+    // open streams, dropping the sender ends the stream
+    let mut streams: HashMap<u64, oneshot::Sender<()>> = HashMap::new();
+    // transfers in progress, by request id
+    let mut uploads: HashMap<u64, mpsc::Sender<Chunk>> = HashMap::new();
+    let mut downloads: HashMap<u64, watch::Sender<u64>> = HashMap::new();
+
+    while let Ok(frame) = recv_frame(&mut reader).await {
+        streams.retain(|_, cancel| !cancel.is_closed());
+        uploads.retain(|_, tx| !tx.is_closed());
+        downloads.retain(|_, tx| !tx.is_closed());
+
+        let (id, req) = match frame {
+            Frame::Json(msg) => parse_request(msg),
+            Frame::Chunk(chunk) => {
+                // Never wait on an upload here, every other request of the
+                // session would wait with it. The channel holds a transfer
+                // window, so only a client that ignores it (or doesn't wait
+                // for TransferReady) fills it up; its upload fails.
+                let stream = chunk.stream;
+                match uploads.get(&stream).map(|tx| tx.try_send(chunk)) {
+                    Some(Err(mpsc::error::TrySendError::Full(_))) => {
+                        uploads.remove(&stream);
+                        let err = ErrorResponse::new(
+                            ErrorCode::BadRequest,
+                            "Chunk beyond the transfer window",
+                        );
+                        reply(&writer, stream, Response::Error(err)).await?;
+                    }
+                    // closed: the upload is over and has answered already
+                    Some(_) => {}
+                    None => warn!("Chunk for unknown stream {}", stream),
+                }
+                continue;
+            }
+        };
+
+        let req = match req {
+            Ok(r) => r,
+            Err(e) => {
+                reply(&writer, id, Response::Error(e)).await?;
+                continue;
+            }
+        };
+
+        match req {
+            Request::Hello(_) => {
+                let err = ErrorResponse::new(ErrorCode::BadRequest, "Duplicate hello");
+                reply(&writer, id, Response::Error(err)).await?;
+            }
+            Request::Cancel(c) => {
+                streams.remove(&c.stream);
+            }
+            Request::Subscribe(sub) => {
+                let (cancel_tx, cancel_rx) = oneshot::channel();
+                streams.insert(id, cancel_tx);
 
-    tracing::info!(
-        "Raw message: {}",
-        serde_json::to_string_pretty(&msg).unwrap()
-    );
+                let events = shared.events.subscribe();
+                tokio::spawn(stream_events(id, sub, events, cancel_rx, writer.clone()));
+            }
+            Request::Upload(up) => {
+                let (tx, rx) = mpsc::channel(TRANSFER_WINDOW as usize);
+                uploads.insert(id, tx);
+
+                let (shared, w) = (shared.clone(), writer.clone());
+                spawn_reply(id, writer.clone(), async move {
+                    if let Some(denied) = authorize(peer, &shared, up.daemon_token.clone()).await {
+                        return Ok(denied);
+                    }
+                    crate::transfer::receive(id, up, rx, w).await
+                });
+            }
+            Request::Download(down) => {
+                let (tx, rx) = watch::channel(down.offset);
+                downloads.insert(id, tx);
+
+                let (shared, w) = (shared.clone(), writer.clone());
+                spawn_reply(id, writer.clone(), async move {
+                    if let Some(denied) = authorize(peer, &shared, down.daemon_token.clone()).await
+                    {
+                        return Ok(denied);
+                    }
+                    crate::transfer::send(id, down, rx, w).await
+                });
+            }
+            Request::TransferAck(ack) => {
+                if let Some(tx) = downloads.get(&ack.stream) {
+                    let _ = tx.send(ack.offset);
+                }
+            }
+            Request::Deploy(deploy) => {
+                // shutdown waits for the deploy and its reply
+                let guard = match shared.lifecycle.start_deploy() {
+                    Ok(g) => g,
+                    Err(e) => {
+                        let resp = common::DeployResponse {
+                            success: false,
+                            message: e.to_string(),
+                            app_dir: None,
+                        };
+                        reply(&writer, id, Response::Deploy(resp)).await?;
+                        continue;
+                    }
+                };
+
+                let shared = shared.clone();
+                let task = spawn_reply(id, writer.clone(), async move {
+                    handle_deploy(peer, &shared, deploy).await
+                });
+                tokio::spawn(async move {
+                    let _ = task.await;
+                    drop(guard);
+                });
+            }
+            req => {
+                let shared = shared.clone();
+                spawn_reply(id, writer.clone(), async move {
+                    dispatch(req, peer, &shared).await
+                });
+            }
+        }
+    }
 
-    let msg_type = msg.get("msg_type").and_then(|v| v.as_str()).unwrap_or("");
+    info!("Session closed: {}", peer);
+    Ok(())
+}
 
-    match msg_type {
-        "register_token" => {
-            let req: RegisterTokenRequest = serde_json::from_value(msg)?;
-            handle_register_token(socket, req).await
+// run a handler in its own task, its result (or error) is the reply
+fn spawn_reply<F>(id: u64, writer: Writer, handler: F) -> tokio::task::JoinHandle<()>
+where
+    F: Future<Output = Result<Response>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let resp = handler.await.unwrap_or_else(|e| {
+            error!("Handler error: {}", e);
+            Response::Error(ErrorResponse::new(ErrorCode::Internal, e.to_string()))
+        });
+        if let Err(e) = reply(&writer, id, resp).await {
+            error!("Reply failed: {}", e);
         }
-        "deploy" => {
-            let req: DeployRequest = serde_json::from_value(msg)?;
-            handle_deploy(socket, routes, health_pids, req).await
+    })
+}
+
+async fn dispatch(req: Request, peer: Peer, shared: &Shared) -> Result<Response> {
+    match req {
+        Request::RegisterToken(req) => handle_register_token(peer, req),
+        Request::Manage(req) => {
+            if let Some(denied) = authorize(peer, shared, req.daemon_token.clone()).await {
+                return Ok(denied);
+            }
+            Ok(handle_manage(shared, req).await)
         }
-        "manage" => {
-            let req: ManageRequest = serde_json::from_value(msg)?;
-            handle_manage(socket, req).await
+        Request::Status(req) => handle_status(peer, shared, req.daemon_token).await,
+        Request::Pairing(req) => handle_pairing(peer, req),
+        Request::Upgrade(req) => {
+            if let Some(denied) = authorize(peer, shared, req.daemon_token.clone()).await {
+                return Ok(denied);
+            }
+            crate::upgrade::handle(req, shared.lifecycle.clone()).await
         }
-        _ => {
-            warn!("Unknown message type: {}", msg_type);
-            Ok(())
+        other => anyhow::bail!("'{}' is not a plain request", other.kind()),
+    }
+}
+
+async fn stream_events(
+    id: u64,
+    sub: SubscribeRequest,
+    mut events: broadcast::Receiver<Event>,
+    mut cancel: oneshot::Receiver<()>,
+    writer: Writer,
+) {
+    loop {
+        let event = tokio::select! {
+            _ = &mut cancel => break,
+            e = events.recv() => e,
+        };
+
+        let event = match event {
+            Ok(e) => e,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("Subscriber {} lagged, {} events dropped", id, n);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        if !sub.apps.is_empty() && !sub.apps.contains(&event.app) {
+            continue;
+        }
+        if reply(&writer, id, Response::Event(event)).await.is_err() {
+            return;
         }
     }
+
+    let _ = reply(&writer, id, Response::StreamEnd).await;
+}
+
+pub async fn reply(writer: &Writer, id: u64, msg: Response) -> Result<()> {
+    send_json(&mut *writer.lock().await, &Envelope { id, msg }).await
 }
 
-async fn handle_manage(
-    mut socket: tokio_rustls::server::TlsStream<TcpStream>,
-    req: ManageRequest,
-) -> Result<()> {
-    let result = match req.action.as_str() {
-        "start" => start_app(&req.app),
-        "stop" => stop_app(&req.app),
-        "restart" => restart_app(&req.app),
-        "rollback" => rollback_app(&req.app),
+// (envelope id, request), Err(..) is a protocol error to send back
+fn parse_request(msg: serde_json::Value) -> (u64, std::result::Result<Request, ErrorResponse>) {
+    let id = msg.get("id").and_then(|v| v.as_u64()).unwrap_or(0);
+    let msg_type = msg
+        .get("msg_type")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+    // the rest may carry tokens and passwords
+    info!("Request {} ({})", msg_type, id);
+
+    let req = serde_json::from_value(msg).map_err(|e| {
+        if msg_type == "hello" || CAPABILITIES.contains(&msg_type.as_str()) {
+            ErrorResponse::new(ErrorCode::BadRequest, format!("Bad '{}': {}", msg_type, e))
+        } else {
+            warn!("Unknown message type: {}", msg_type);
+            ErrorResponse::new(
+                ErrorCode::UnknownRequest,
+                format!("Unknown message type: '{}'", msg_type),
+            )
+        }
+    });
+
+    (id, req)
+}
+
+pub fn emit(events: &Events, kind: &str, app: &str, message: impl Into<String>) {
+    // no subscribers is fine
+    let _ = events.send(Event {
+        kind: kind.to_string(),
+        app: app.to_string(),
+        message: message.into(),
+        timestamp: chrono::Utc::now().timestamp(),
+    });
+}
+
+async fn handle_manage(shared: &Shared, req: ManageRequest) -> Response {
+    // stops wait out the grace period and backups copy whole volumes, keep
+    // them off the runtime
+    let (app, action, arg) = (req.app.clone(), req.action.clone(), req.arg.clone());
+    let result = tokio::task::spawn_blocking(move || match action.as_str() {
+        "start" => start_app(&app),
+        "stop" => stop_app(&app),
+        "restart" => restart_app(&app),
+        "rollback" => rollback_app(&app),
+        "volume" => volume_info(&app),
+        "backup" => backup_volume(&app),
+        "restore" => restore_volume(&app, arg.as_deref()),
         _ => Err(anyhow::anyhow!("Unknown action")),
-    };
+    })
+    .await
+    .unwrap_or_else(|e| Err(anyhow::anyhow!("{} failed: {}", req.action, e)));
 
     let response = match result {
-        Ok(msg) => ManageResponse {
-            success: true,
-            message: msg,
-        },
-        Err(e) => ManageResponse {
-            success: false,
-            message: e.to_string(),
-        },
+        Ok(msg) => {
+            emit(
+                &shared.events,
+                &format!("app_{}", req.action),
+                &req.app,
+                &msg,
+            );
+            ManageResponse {
+                success: true,
+                message: msg,
+            }
+        }
+        Err(e) => {
+            emit(&shared.events, "manage_failed", &req.app, e.to_string());
+            ManageResponse {
+                success: false,
+                message: e.to_string(),
+            }
+        }
     };
 
-    common::send_json(&mut socket, &response).await
+    Response::Manage(response)
 }
 
-fn start_app(app: &str) -> Result<String> {
-    let dir = common::app_dir(app);
+pub fn start_app(app: &str) -> Result<String> {
+    let dir = crate::config::get().app_dir(app);
     let mut state = common::load_state(&dir)?.ok_or_else(|| anyhow::anyhow!("App not found"))?;
 
     if state.status == "running" {
@@ -171,133 +584,325 @@ fn start_app(app: &str) -> Result<String> {
     let config = common::load_app_config(&dir)?;
     let run = config
         .run
+        .as_ref()
         .ok_or_else(|| anyhow::anyhow!("No [run] section"))?;
+    if let Some(volume) = crate::storage::Volume::of(&config, &dir)? {
+        volume.check_quota()?;
+    }
 
-    let child = std::process::Command::new("systemd-run")
-        .args(["--user", "--scope", "sh", "-c", &run.command])
-        .current_dir(&dir)
-        .spawn()?;
-
-    let pid = child.id();
+    crate::hooks::run_for_app(&config, &dir, "pre_start")?;
+    let pid = crate::deploy::spawn(run, &config, &dir)?;
+    if let Err(e) = crate::hooks::run_for_app(&config, &dir, "post_start") {
+        let _ = crate::process::signal(pid, libc::SIGTERM);
+        return Err(e);
+    }
 
     state.status = "running".into();
     state.pid = Some(pid);
+    state.health = None;
     common::save_state(&dir, &state)?;
+    crate::health::watch(&dir, &config, false);
 
     Ok(format!("Started with PID {}", pid))
 }
 
 fn stop_app(app: &str) -> Result<String> {
-    let dir = common::app_dir(app);
+    let dir = crate::config::get().app_dir(app);
     let mut state = common::load_state(&dir)?.ok_or_else(|| anyhow::anyhow!("App not found"))?;
 
+    // a failing pre_stop hook doesn't keep the app running
+    if state.pid.is_some()
+        && let Ok(config) = common::load_app_config(&dir)
+        && let Err(e) = crate::hooks::run_for_app(&config, &dir, "pre_stop")
+    {
+        warn!("{}", e);
+    }
+
     if let Some(pid) = state.pid {
-        let _ = std::process::Command::new("kill")
-            .arg(pid.to_string())
-            .status();
+        crate::process::stop(pid, crate::config::get().shutdown_timeout());
     }
 
     state.status = "stopped".into();
     state.pid = None;
+    state.health = None;
+    crate::adopt::forget(&dir);
+    crate::health::unwatch(&dir);
     common::save_state(&dir, &state)?;
 
     Ok("Stopped".into())
 }
 
-fn restart_app(app: &str) -> Result<String> {
+pub fn restart_app(app: &str) -> Result<String> {
+    crate::metrics::restart(app);
     stop_app(app)?;
     std::thread::sleep(std::time::Duration::from_millis(500));
     start_app(app)
 }
 
-fn rollback_app(app: &str) -> Result<String> {
-    let dir = common::app_dir(app);
-    let versions = dir.join("versions");
+// Swaps the release for the newest copy in versions/, the one the last
+// deploy replaced. A running app is started again on the old release.
+pub fn rollback_app(app: &str) -> Result<String> {
+    let dir = crate::config::get().app_dir(app);
+    let latest =
+        crate::hooks::latest_backup(&dir).ok_or_else(|| anyhow::anyhow!("No backups found"))?;
+    crate::deploy::check_backup(&latest)?;
+
+    let running = common::load_state(&dir)?
+        .filter(|s| s.status == "running")
+        .and_then(|s| s.pid);
+    if let Some(pid) = running {
+        stop_app(app)?;
+        // the old release wants the same port
+        crate::process::wait_gone(pid, crate::config::get().shutdown_timeout());
+    }
 
-    let mut entries: Vec<_> = std::fs::read_dir(&versions)?
-        .filter_map(|e| e.ok())
-        .collect();
+    crate::deploy::restore(&dir, &latest)?;
+    let config = common::load_app_config(&dir)?;
+    if let Some(mut state) = common::load_state(&dir)? {
+        state.version = config.app.version.clone();
+        common::save_state(&dir, &state)?;
+    }
+    info!("Rolled {} back to {}", app, config.app.version);
 
-    entries.sort_by_key(|e| e.path());
+    if running.is_some() {
+        start_app(app)?;
+    }
 
-    let latest = entries
-        .last()
-        .ok_or_else(|| anyhow::anyhow!("No backups found"))?;
+    if let Err(e) = crate::hooks::run_for_app(&config, &dir, "on_rollback") {
+        warn!("{}", e);
+    }
 
-    let current = dir.join("current");
+    Ok(format!("Rolled back to {}", config.app.version))
+}
 
-    if current.exists() {
-        std::fs::remove_file(&current)?;
-    }
+fn volume(app: &str) -> Result<(PathBuf, common::AppConfig, crate::storage::Volume)> {
+    let dir = crate::config::get().app_dir(app);
+    let config = common::load_app_config(&dir)?;
+    let volume = crate::storage::Volume::of(&config, &dir)?
+        .ok_or_else(|| anyhow::anyhow!("{} has no local [storage]", app))?;
+    Ok((dir, config, volume))
+}
 
-    // restart if running
-    let state = common::load_state(&dir)?;
-    if let Some(s) = state {
-        if s.status == "running" {
-            restart_app(app)?;
+fn volume_info(app: &str) -> Result<String> {
+    let (_, _, volume) = volume(app)?;
+    let size = match volume.quota {
+        Some(q) => format!("{} of {} bytes", volume.usage(), q),
+        None => format!("{} bytes", volume.usage()),
+    };
+    let backups: Vec<String> = volume
+        .backups()
+        .iter()
+        .filter_map(|b| b.file_name())
+        .map(|b| b.to_string_lossy().into_owned())
+        .collect();
+    Ok(format!(
+        "{} ({}), backups: {}",
+        volume.path.display(),
+        size,
+        if backups.is_empty() {
+            "none".to_string()
+        } else {
+            backups.join(", ")
         }
+    ))
+}
+
+fn backup_volume(app: &str) -> Result<String> {
+    let (_, _, volume) = volume(app)?;
+    Ok(format!("Backed up to {}", volume.backup()?))
+}
+
+// a running app is stopped for it and started again
+fn restore_volume(app: &str, backup: Option<&str>) -> Result<String> {
+    let (dir, config, volume) = volume(app)?;
+    let running = common::load_state(&dir)?.is_some_and(|s| s.status == "running");
+    if running {
+        stop_app(app)?;
     }
 
-    Ok("Rolled back".into())
+    let identity = crate::identity::for_app(&config, &dir)?;
+    let restored = volume.restore(backup, identity.as_ref());
+    if running {
+        start_app(app)?;
+    }
+    Ok(format!("Restored {}", restored?))
 }
 
-async fn handle_register_token(
-    mut socket: tokio_rustls::server::TlsStream<TcpStream>,
-    req: common::RegisterTokenRequest,
-) -> Result<()> {
+fn handle_register_token(peer: Peer, req: common::RegisterTokenRequest) -> Result<Response> {
     let mut store = load_tokens();
-    // add token hash
-    store.tokens.push(req.token_hash);
+
+    // on-device registration is trusted, network ones wait for approval unless auto-approved
+    let auto_approve = crate::config::get().pairing.auto_approve;
+    let pending = matches!(peer, Peer::Remote(_)) && !auto_approve;
+
+    if pending {
+        let id = store.pending.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+        store.pending.push(PendingToken {
+            id,
+            peer: peer.to_string(),
+            requested: chrono::Utc::now().timestamp(),
+            token_hash: req.token_hash,
+        });
+        info!(
+            "Token from {} waits for approval: flare --local pairing approve {}",
+            peer, id
+        );
+    } else {
+        // add token hash
+        store.tokens.push(req.token_hash);
+        info!("Registered new token");
+    }
 
     save_tokens(&store)?;
-    info!("Registered new token");
 
-    let resp = common::RegisterTokenResponse { success: true };
-    common::send_json(&mut socket, &resp).await?;
+    Ok(Response::RegisterToken(common::RegisterTokenResponse {
+        success: true,
+        pending,
+    }))
+}
 
-    Ok(())
+fn handle_pairing(peer: Peer, req: PairingRequest) -> Result<Response> {
+    if !matches!(peer, Peer::Local(_)) {
+        let err = ErrorResponse::new(ErrorCode::Unauthorized, "Pairing is local socket only");
+        return Ok(Response::Error(err));
+    }
+
+    let mut store = load_tokens();
+    let message = match (req.action.as_str(), req.token) {
+        ("list", _) => format!("{} pending", store.pending.len()),
+        (action @ ("approve" | "reject"), Some(id)) => {
+            let idx = store
+                .pending
+                .iter()
+                .position(|p| p.id == id)
+                .ok_or_else(|| anyhow::anyhow!("No pending token {}", id))?;
+            let token = store.pending.remove(idx);
+
+            if action == "approve" {
+                store.tokens.push(token.token_hash);
+            }
+            save_tokens(&store)?;
+            info!("Token {} from {}: {}", id, token.peer, action);
+            format!("Token {} from {}: {}d", id, token.peer, action)
+        }
+        ("approve" | "reject", None) => anyhow::bail!("Missing token id"),
+        (other, _) => anyhow::bail!("Unknown pairing action: {}", other),
+    };
+
+    Ok(Response::Pairing(PairingResponse {
+        message,
+        pending: store.pending,
+    }))
 }
 
-async fn handle_deploy(
-    mut socket: tokio_rustls::server::TlsStream<TcpStream>,
-    routes: Routes,
-    health_pids: HealthPids,
-    req: common::DeployRequest,
-) -> Result<()> {
-    // verify token
+async fn handle_status(peer: Peer, shared: &Shared, token: Option<String>) -> Result<Response> {
+    if let Some(denied) = authorize(peer, shared, token).await {
+        return Ok(denied);
+    }
+
+    let (device, apps) =
+        tokio::task::spawn_blocking(|| (crate::sysinfo::device_status(), crate::sysinfo::apps()))
+            .await?;
+
+    Ok(Response::Status(StatusResponse {
+        daemon: format!("flared {}", env!("CARGO_PKG_VERSION")),
+        apps,
+        device: Some(device),
+    }))
+}
+
+// checks the daemon token, Some(error response) if the peer is not allowed in
+async fn authorize(peer: Peer, shared: &Shared, token: Option<String>) -> Option<Response> {
+    // local peers were checked by uid already
+    let addr = match peer {
+        Peer::Remote(addr) => addr,
+        Peer::Local(_) => return None,
+    };
+
+    // other connections of the peer wait out its delay too
+    if let Some(wait) = shared.limiter.retry_after(addr.ip()) {
+        warn!("Refused token check from {}, retry in {:?}", peer, wait);
+        let err = ErrorResponse::new(
+            ErrorCode::Unauthorized,
+            format!(
+                "Too many failed attempts, retry in {}s",
+                wait.as_secs().max(1)
+            ),
+        );
+        return Some(Response::Error(err));
+    }
+
     let store = load_tokens();
 
-    let token = req.daemon_token.as_deref().unwrap_or("");
-    let valid = store
-        .tokens
-        .iter()
-        .any(|hash| common::verify_token(token, hash));
-
-    if !valid {
-        warn!("Invalid token");
-        let response = common::DeployResponse {
-            success: false,
-            message: "Invalid token".into(),
-            app_dir: None,
-        };
-        common::send_json(&mut socket, &response).await?;
-        return Ok(());
+    let token = token.unwrap_or_default();
+    // argon2 is slow on purpose, don't let guesses eat every core
+    let _slot = shared.limiter.verifications.acquire().await.ok();
+    let valid = tokio::task::spawn_blocking(move || {
+        store
+            .tokens
+            .iter()
+            .any(|hash| common::verify_token(&token, hash))
+    })
+    .await
+    .unwrap_or(false);
+
+    if valid {
+        shared.limiter.record_success(addr.ip());
+        return None;
     }
 
-    info!("Deploy: {}", req.repo);
+    let delay = shared.limiter.record_failure(addr.ip());
+    warn!("Invalid token from {}, delaying {:?}", peer, delay);
+    tokio::time::sleep(delay).await;
+
+    let err = ErrorResponse::new(ErrorCode::Unauthorized, "Invalid token");
+    Some(Response::Error(err))
+}
+
+async fn handle_deploy(
+    peer: Peer,
+    shared: &Shared,
+    req: common::DeployRequest,
+) -> Result<Response> {
+    if let Some(denied) = authorize(peer, shared, req.daemon_token.clone()).await {
+        return Ok(denied);
+    }
 
-    let response = match crate::deploy::run(&req, routes, health_pids).await {
-        Ok(dir) => common::DeployResponse {
-            success: true,
-            message: format!("Deployed to {}", dir.display()),
-            app_dir: Some(dir.to_string_lossy().into()),
-        },
-        Err(e) => common::DeployResponse {
-            success: false,
-            message: e.to_string(),
-            app_dir: None,
-        },
+    info!("Deploy: {}", req.repo);
+    let app = req.repo.replace("/", "_");
+    emit(&shared.events, "deploy_started", &app, &req.repo);
+
+    let routes = shared.routes.clone();
+    let health_pids = shared.health_pids.clone();
+    let started = std::time::Instant::now();
+    let result = crate::deploy::run(&req, routes, health_pids, &shared.lifecycle).await;
+    crate::metrics::deploy(&app, result.is_ok(), started.elapsed());
+    let response = match result {
+        Ok(dir) => {
+            crate::notify::send(&dir, "deploy_success", "deployed");
+            emit(
+                &shared.events,
+                "deploy_finished",
+                &app,
+                dir.to_string_lossy(),
+            );
+            common::DeployResponse {
+                success: true,
+                message: format!("Deployed to {}", dir.display()),
+                app_dir: Some(dir.to_string_lossy().into()),
+            }
+        }
+        Err(e) => {
+            emit(&shared.events, "deploy_failed", &app, e.to_string());
+            let dir = crate::config::get().app_dir(&req.repo);
+            crate::notify::send(&dir, "deploy_failed", e.to_string());
+            common::DeployResponse {
+                success: false,
+                message: e.to_string(),
+                app_dir: None,
+            }
+        }
     };
 
-    common::send_json(&mut socket, &response).await
+    Ok(Response::Deploy(response))
 }
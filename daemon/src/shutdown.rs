@@ -0,0 +1,143 @@
+use anyhow::Result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+pub type Lifecycle = Arc<Shutdown>;
+
+// Tracks in-flight deploys so SIGTERM doesn't leave apps half-extracted
+pub struct Shutdown {
+    deploys: watch::Sender<usize>,
+    // no new deploys once set
+    closing: AtomicBool,
+    // grace period is over, running deploys bail at their next step
+    aborting: AtomicBool,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            deploys: watch::channel(0).0,
+            closing: AtomicBool::new(false),
+            aborting: AtomicBool::new(false),
+        }
+    }
+}
+
+// held by a running deploy
+pub struct DeployGuard(Lifecycle);
+
+impl Drop for DeployGuard {
+    fn drop(&mut self) {
+        self.0.deploys.send_modify(|n| *n -= 1);
+    }
+}
+
+impl Shutdown {
+    pub fn start_deploy(self: &Arc<Self>) -> Result<DeployGuard> {
+        if self.closing.load(Ordering::SeqCst) {
+            anyhow::bail!("Daemon is shutting down");
+        }
+        self.deploys.send_modify(|n| *n += 1);
+        Ok(DeployGuard(self.clone()))
+    }
+
+    // called by deploys between steps
+    pub fn check(&self) -> Result<()> {
+        if self.aborting.load(Ordering::SeqCst) {
+            anyhow::bail!("Deploy aborted, daemon is shutting down");
+        }
+        Ok(())
+    }
+
+    // upgrade fell through, back to business
+    pub fn reopen(&self) {
+        self.closing.store(false, Ordering::SeqCst);
+    }
+
+    // waits for running deploys, aborts the ones still going after `grace`
+    pub async fn drain(&self, grace: Duration) {
+        self.closing.store(true, Ordering::SeqCst);
+
+        let mut deploys = self.deploys.subscribe();
+        let running = *deploys.borrow();
+        if running == 0 {
+            return;
+        }
+
+        info!("Waiting for {} deploy(s) to finish", running);
+        if tokio::time::timeout(grace, deploys.wait_for(|n| *n == 0))
+            .await
+            .is_ok()
+        {
+            return;
+        }
+
+        warn!("Deploys still running after {:?}, rolling back", grace);
+        self.aborting.store(true, Ordering::SeqCst);
+        // a blocked build step can't be interrupted, don't hang on it forever
+        let _ = tokio::time::timeout(grace, deploys.wait_for(|n| *n == 0)).await;
+    }
+}
+
+// SIGTERM or ctrl-c
+pub async fn signal_received() -> Result<()> {
+    let mut term = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = term.recv() => info!("Got SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("Got SIGINT"),
+    }
+    Ok(())
+}
+
+// SIGTERM to every running app, SIGKILL to what is left after `grace`
+pub async fn stop_apps(grace: Duration) {
+    let apps_dir = crate::config::get().apps_dir();
+    let entries = match std::fs::read_dir(&apps_dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    let mut stopping = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let dir = entry.path();
+        let mut state = match common::load_state(&dir) {
+            Ok(Some(s)) => s,
+            _ => continue,
+        };
+        if state.status != "running" {
+            continue;
+        }
+
+        if let Some(pid) = state.pid {
+            info!("Stopping {} (PID {})", state.name, pid);
+            let _ = crate::process::signal(pid, libc::SIGTERM);
+            stopping.push((state.name.clone(), pid));
+        }
+
+        state.status = "stopped".into();
+        state.pid = None;
+        crate::adopt::forget(&dir);
+        if let Err(e) = common::save_state(&dir, &state) {
+            warn!("Can't save state of {}: {}", state.name, e);
+        }
+    }
+
+    let deadline = tokio::time::Instant::now() + grace;
+    while stopping.iter().any(|(_, pid)| crate::process::alive(*pid)) {
+        if tokio::time::Instant::now() >= deadline {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    for (name, pid) in stopping {
+        if crate::process::alive(pid) {
+            warn!("{} (PID {}) ignored SIGTERM, killing", name, pid);
+            let _ = crate::process::signal(pid, libc::SIGKILL);
+        }
+    }
+}
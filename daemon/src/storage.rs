@@ -0,0 +1,302 @@
+use anyhow::Result;
+use common::AppConfig;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::path::{Component, Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::identity::Identity;
+
+// [storage] type = "local": a volume that outlives releases, kept in
+// <data_dir>/volumes/<app> and symlinked into every release at `mount`.
+// Backups are tarballs next to it in <app>.backups.
+
+const DEFAULT_MOUNT: &str = "data";
+// backups kept per app, oldest go first
+const BACKUPS_KEPT: usize = 10;
+
+pub struct Volume {
+    pub path: PathBuf,
+    // the release, and where in it the symlink goes
+    release: PathBuf,
+    mount: PathBuf,
+    // bytes, from `size`
+    pub quota: Option<u64>,
+}
+
+impl Volume {
+    // None unless the app has a local volume
+    pub fn of(config: &AppConfig, dir: &Path) -> Result<Option<Self>> {
+        let Some(storage) = &config.storage else {
+            return Ok(None);
+        };
+        match storage.r#type.as_str() {
+            "local" => {}
+            "s3" => return Ok(None),
+            other => anyhow::bail!("Unknown storage type: {}", other),
+        }
+
+        let mount = Path::new(storage.mount.as_deref().unwrap_or(DEFAULT_MOUNT));
+        let inside = mount
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !inside || mount.as_os_str().is_empty() {
+            anyhow::bail!("storage mount must be a path inside the app: {:?}", mount);
+        }
+
+        let app = dir.file_name().unwrap_or_default();
+        Ok(Some(Self {
+            path: crate::config::get().volumes_dir().join(app),
+            release: dir.to_path_buf(),
+            mount: mount.to_path_buf(),
+            quota: storage
+                .size
+                .as_deref()
+                .map(crate::cgroup::parse_size)
+                .transpose()?,
+        }))
+    }
+
+    // <app><suffix> next to the volume
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(suffix);
+        self.path.with_file_name(name)
+    }
+
+    fn backups_dir(&self) -> PathBuf {
+        self.sibling(".backups")
+    }
+
+    // Links the volume into the release. Whatever the release shipped at
+    // `mount` seeds the volume, files already in it win.
+    pub fn attach(&self, identity: Option<&Identity>) -> Result<()> {
+        std::fs::create_dir_all(&self.path)?;
+
+        // a parent linking out of the release would have us move and delete
+        // whatever is at the other end
+        let mount = common::path_inside(&self.release, &self.mount, true)?;
+        match std::fs::symlink_metadata(&mount) {
+            Ok(m) if m.is_symlink() => std::fs::remove_file(&mount)?,
+            Ok(m) if m.is_dir() => {
+                seed(&mount, &self.path)?;
+                std::fs::remove_dir_all(&mount)?;
+            }
+            Ok(_) => anyhow::bail!("storage mount {:?} is a file", mount),
+            Err(_) => {}
+        }
+        std::os::unix::fs::symlink(&self.path, &mount)?;
+
+        if let Some(id) = identity {
+            id.own(&self.path)?;
+            id.own(&mount)?;
+        }
+        self.check_quota()?;
+        info!("Volume {:?} at {:?}", self.path, mount);
+        Ok(())
+    }
+
+    // Unlinks the volume from the release in `dir`, as its current config
+    // has it, so a new archive unpacks into the release itself.
+    pub fn detach(dir: &Path) -> Option<Self> {
+        let config = common::load_app_config(&dir.to_path_buf()).ok()?;
+        let volume = Self::of(&config, dir).ok()??;
+        if let Ok(mount) = common::path_inside(dir, &volume.mount, false)
+            && std::fs::symlink_metadata(&mount).is_ok_and(|m| m.is_symlink())
+        {
+            let _ = std::fs::remove_file(&mount);
+        }
+        Some(volume)
+    }
+
+    // `name` in the release, stored in the volume: the release keeps a
+    // symlink. An existing file in the release moves in first. Returns where
+    // it is stored.
+    pub fn keep(&self, dir: &Path, name: &str) -> Result<PathBuf> {
+        let link = common::path_inside(dir, Path::new(name), false)?;
+        let file_name = link
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Bad file name: {}", name))?;
+        let stored = self.path.join(file_name);
+
+        match std::fs::symlink_metadata(&link) {
+            Ok(m) if m.is_symlink() => std::fs::remove_file(&link)?,
+            Ok(_) if !stored.exists() => move_path(&link, &stored)?,
+            Ok(_) => {
+                warn!(
+                    "{:?} is in the volume already, ignoring the release's",
+                    stored
+                );
+                std::fs::remove_file(&link)?;
+            }
+            Err(_) => {}
+        }
+        std::os::unix::fs::symlink(&stored, &link)?;
+        Ok(stored)
+    }
+
+    // bytes on disk, symlinks not followed
+    pub fn usage(&self) -> u64 {
+        fn walk(path: &Path) -> u64 {
+            let Ok(meta) = std::fs::symlink_metadata(path) else {
+                return 0;
+            };
+            if !meta.is_dir() {
+                return meta.len();
+            }
+            std::fs::read_dir(path)
+                .map(|d| d.filter_map(|e| e.ok()).map(|e| walk(&e.path())).sum())
+                .unwrap_or(0)
+        }
+        walk(&self.path)
+    }
+
+    pub fn check_quota(&self) -> Result<()> {
+        let Some(quota) = self.quota else {
+            return Ok(());
+        };
+        let used = self.usage();
+        if used > quota {
+            anyhow::bail!(
+                "Volume {:?} uses {} bytes, over its size of {}",
+                self.path,
+                used,
+                quota
+            );
+        }
+        Ok(())
+    }
+
+    // newest last
+    pub fn backups(&self) -> Vec<PathBuf> {
+        let mut backups: Vec<PathBuf> = std::fs::read_dir(self.backups_dir())
+            .map(|d| d.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+            .unwrap_or_default();
+        backups.retain(|p| p.to_string_lossy().ends_with(".tar.gz"));
+        backups.sort();
+        backups
+    }
+
+    // <timestamp>.tar.gz of the whole volume, returns its name
+    pub fn backup(&self) -> Result<String> {
+        std::fs::create_dir_all(self.backups_dir())?;
+        let name = format!("{}.tar.gz", chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+        let path = self.backups_dir().join(&name);
+        let partial = path.with_extension("partial");
+
+        let file = std::fs::File::create(&partial)?;
+        let mut tar = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        tar.follow_symlinks(false);
+        tar.append_dir_all(".", &self.path)?;
+        tar.into_inner()?.finish()?;
+        std::fs::rename(&partial, &path)?;
+        info!("Backed up {:?} to {:?}", self.path, path);
+
+        let backups = self.backups();
+        for old in backups
+            .iter()
+            .take(backups.len().saturating_sub(BACKUPS_KEPT))
+        {
+            let _ = std::fs::remove_file(old);
+        }
+        Ok(name)
+    }
+
+    // Replaces the volume with a backup, the newest without `name`. The old
+    // contents stay until the new ones are in place.
+    pub fn restore(&self, name: Option<&str>, identity: Option<&Identity>) -> Result<String> {
+        let backup = match name {
+            Some(name) => {
+                let path = self.backups_dir().join(name);
+                if Path::new(name).components().count() != 1 || !path.exists() {
+                    anyhow::bail!("No backup {}", name);
+                }
+                path
+            }
+            None => self
+                .backups()
+                .pop()
+                .ok_or_else(|| anyhow::anyhow!("No backups found"))?,
+        };
+
+        let staging = self.sibling(".restore");
+        let old = self.sibling(".old");
+        let _ = std::fs::remove_dir_all(&staging);
+        std::fs::create_dir_all(&staging)?;
+        tar::Archive::new(GzDecoder::new(std::fs::File::open(&backup)?)).unpack(&staging)?;
+        if let Some(id) = identity {
+            id.own(&staging)?;
+        }
+
+        let _ = std::fs::remove_dir_all(&old);
+        if self.path.exists() {
+            std::fs::rename(&self.path, &old)?;
+        }
+        std::fs::rename(&staging, &self.path)?;
+        let _ = std::fs::remove_dir_all(&old);
+
+        let name = backup.file_name().unwrap_or_default().to_string_lossy();
+        info!("Restored {:?} from {}", self.path, name);
+        Ok(name.into_owned())
+    }
+}
+
+// moves what `from` has and `to` doesn't
+fn seed(from: &Path, to: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if std::fs::symlink_metadata(&target).is_err() {
+            move_path(&entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+// rename, or copy when the volume is on another filesystem
+fn move_path(from: &Path, to: &Path) -> Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let status = std::process::Command::new("cp")
+        .arg("-a")
+        .arg(from)
+        .arg(to)
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("Can't move {:?} to {:?}", from, to);
+    }
+    if from.is_dir() {
+        std::fs::remove_dir_all(from)?;
+    } else {
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+// Apps over their volume size get a warning now and then; deploys and
+// starts refuse them outright.
+pub async fn watch_quotas() {
+    const INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+    loop {
+        tokio::time::sleep(INTERVAL).await;
+        let _ = tokio::task::spawn_blocking(|| {
+            let Ok(entries) = std::fs::read_dir(crate::config::get().apps_dir()) else {
+                return;
+            };
+            for entry in entries.filter_map(|e| e.ok()) {
+                let dir = entry.path();
+                let Ok(config) = common::load_app_config(&dir) else {
+                    continue;
+                };
+                if let Ok(Some(volume)) = Volume::of(&config, &dir)
+                    && let Err(e) = volume.check_quota()
+                {
+                    warn!("{}", e);
+                }
+            }
+        })
+        .await;
+    }
+}
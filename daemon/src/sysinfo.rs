@@ -0,0 +1,110 @@
+use common::{AppState, DeviceStatus};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+// state of every deployed app, by name
+pub fn apps() -> Vec<AppState> {
+    apps_by_dir().into_iter().map(|(_, state)| state).collect()
+}
+
+// the same with the name of each app's directory
+pub fn apps_by_dir() -> Vec<(String, AppState)> {
+    let mut apps = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(crate::config::get().apps_dir()) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            if let Ok(Some(mut state)) = common::load_state(&entry.path()) {
+                crate::cgroup::refresh_oom(&entry.path(), &mut state);
+                apps.push((entry.file_name().to_string_lossy().into_owned(), state));
+            }
+        }
+    }
+    apps.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+    apps
+}
+
+pub fn device_status() -> DeviceStatus {
+    let (memory_total, memory_available) = memory();
+    let (disk_total, disk_free) = disk(&crate::config::get().apps_dir());
+    let now = chrono::Utc::now().timestamp();
+    let started = crate::process::stats(std::process::id())
+        .map(|s| s.started)
+        .unwrap_or(now);
+
+    DeviceStatus {
+        hostname: hostname(),
+        uptime: read("/proc/uptime")
+            .and_then(|u| u.split('.').next()?.parse().ok())
+            .unwrap_or(0),
+        daemon_uptime: (now - started).max(0) as u64,
+        load: load(),
+        cpus: std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1),
+        memory_total,
+        memory_available,
+        disk_total,
+        disk_free,
+        temperature: temperature(),
+    }
+}
+
+pub fn hostname() -> String {
+    read("/proc/sys/kernel/hostname").unwrap_or_default()
+}
+
+fn read(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+fn load() -> [f64; 3] {
+    let mut load = [0.0; 3];
+    if let Some(avg) = read("/proc/loadavg") {
+        for (slot, value) in load.iter_mut().zip(avg.split_whitespace()) {
+            *slot = value.parse().unwrap_or(0.0);
+        }
+    }
+    load
+}
+
+// (total, available) in bytes
+fn memory() -> (u64, u64) {
+    let meminfo = read("/proc/meminfo").unwrap_or_default();
+    // "MemTotal:  2035532 kB"
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|l| l.strip_prefix(name))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+            .unwrap_or(0)
+    };
+    (field("MemTotal:"), field("MemAvailable:"))
+}
+
+// (total, free to unprivileged users) in bytes
+fn disk(path: &Path) -> (u64, u64) {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return (0, 0);
+    };
+    let mut vfs: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut vfs) } != 0 {
+        return (0, 0);
+    }
+    let block = vfs.f_frsize as u64;
+    (vfs.f_blocks as u64 * block, vfs.f_bavail as u64 * block)
+}
+
+fn temperature() -> Option<f64> {
+    std::fs::read_dir("/sys/class/thermal")
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with("thermal_zone"))
+        .filter_map(|e| std::fs::read_to_string(e.path().join("temp")).ok())
+        // millidegrees
+        .filter_map(|t| t.trim().parse::<f64>().ok())
+        .map(|t| t / 1000.0)
+        .reduce(f64::max)
+}
@@ -0,0 +1,124 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+// flared's config for tests: the defaults, data in a directory of our own
+pub fn config() -> &'static crate::config::Config {
+    let data_dir = std::env::temp_dir().join(format!("flared-test-{}", std::process::id()));
+    crate::config::init(crate::config::Config {
+        data_dir,
+        ..Default::default()
+    });
+    crate::config::get()
+}
+
+// A local HTTP server for tests of things that talk HTTP (pushgateway,
+// webhooks, S3). Answers with the queued responses in order, 200 once they
+// run out, and keeps every request it got.
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    // path and query
+    pub target: String,
+    // names lowercase
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+pub struct Server {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    pub async fn start(responses: Vec<(u16, &str)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let queue: VecDeque<(u16, String)> = responses
+            .into_iter()
+            .map(|(code, body)| (code, body.to_string()))
+            .collect();
+        let queue = Arc::new(Mutex::new(queue));
+
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (seen, queue) = (seen.clone(), queue.clone());
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let Some(request) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let head = request.method == "HEAD";
+                    seen.lock().unwrap().push(request);
+                    let (code, body) = queue
+                        .lock()
+                        .unwrap()
+                        .pop_front()
+                        .unwrap_or((200, String::new()));
+                    let mut reply = format!(
+                        "HTTP/1.1 {} X\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        code,
+                        body.len()
+                    );
+                    if !head {
+                        reply.push_str(&body);
+                    }
+                    let _ = stream.get_mut().write_all(reply.as_bytes()).await;
+                });
+            }
+        });
+        Self { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn read_request(stream: &mut BufReader<tokio::net::TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let len = headers
+        .get("content-length")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await.ok()?;
+    Some(Request {
+        method,
+        target,
+        headers,
+        body,
+    })
+}
@@ -1,5 +1,8 @@
 use anyhow::Result;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio_rustls::{TlsAcceptor, server::TlsStream};
@@ -16,23 +19,58 @@ pub async fn accept(stream: TcpStream) -> Result<TlsStream<TcpStream>> {
     Ok(acceptor.accept(stream).await?)
 }
 
-// try env vars first, fallback to self-signed
+// try configured cert first, then the identity from `flared install`, fallback to self-signed
 fn load_or_generate() -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
-    if let (Ok(cert), Ok(key)) = (
-        std::env::var("FLARE_TLS_CERT"),
-        std::env::var("FLARE_TLS_KEY"),
-    ) {
-        if let Ok(result) = load_files(&cert, &key) {
+    let config = crate::config::get();
+    if let (Some(cert), Some(key)) = (&config.tls.cert, &config.tls.key) {
+        if let Ok(result) = load_files(cert, key) {
             return Ok(result);
         }
     }
+
+    let (cert, key) = identity_paths(&config.data_dir);
+    if let Ok(result) = load_files(&cert, &key) {
+        return Ok(result);
+    }
+
     info!("Don't see TLS_CERT, start generate...");
     generate_cert()
 }
 
+// long-lived self-signed cert of this device
+pub fn identity_paths(data_dir: &Path) -> (PathBuf, PathBuf) {
+    let dir = data_dir.join("tls");
+    (dir.join("cert.pem"), dir.join("key.pem"))
+}
+
+// false if there already is one
+pub fn write_identity(data_dir: &Path) -> Result<bool> {
+    let (cert_path, key_path) = identity_paths(data_dir);
+    if cert_path.exists() && key_path.exists() {
+        return Ok(false);
+    }
+
+    let mut names = vec!["localhost".to_string()];
+    if let Ok(host) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        names.push(host.trim().to_string());
+    }
+    let cert = rcgen::generate_simple_self_signed(names)?;
+
+    std::fs::create_dir_all(cert_path.parent().unwrap())?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&key_path)?
+        .write_all(cert.signing_key.serialize_pem().as_bytes())?;
+    std::fs::write(&cert_path, cert.cert.pem())?;
+    Ok(true)
+}
+
 fn load_files(
-    cert_path: &str,
-    key_path: &str,
+    cert_path: &Path,
+    key_path: &Path,
 ) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
     use std::io::BufReader;
 
@@ -0,0 +1,167 @@
+use anyhow::Result;
+use common::{
+    CHUNK_SIZE, Chunk, DownloadRequest, Response, TRANSFER_WINDOW, TransferAck, TransferDone,
+    TransferReady, TransferTarget, UploadRequest, send_chunk,
+};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tracing::info;
+
+use crate::server::{Writer, reply};
+
+// give up on a peer that stopped sending data or acks
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// `create`: missing directories on the way are made, for uploads
+fn resolve(target: &TransferTarget, create: bool) -> Result<PathBuf> {
+    match target {
+        TransferTarget::AppFile { app, path } => {
+            // app_dir() turns slashes into underscores, "." and ".." are left
+            let dir = crate::config::get().app_dir(app);
+            let deployed = !matches!(app.as_str(), "" | "." | "..")
+                && std::fs::symlink_metadata(&dir).is_ok_and(|m| m.is_dir());
+            if !deployed {
+                anyhow::bail!("No app {}", app);
+            }
+            // files are written as root, not through links the app made
+            common::path_inside(&dir, Path::new(path), create)
+        }
+        TransferTarget::DaemonBinary => {
+            let path = crate::upgrade::staged_path();
+            if create && let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            Ok(path)
+        }
+    }
+}
+
+// partial upload lives next to the target, keyed by transfer id so resumes can't mix files
+fn part_path(dest: &Path, transfer: &str) -> Result<PathBuf> {
+    let valid = !transfer.is_empty()
+        && transfer.len() <= 64
+        && transfer
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        anyhow::bail!("Invalid transfer id: {}", transfer);
+    }
+
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    Ok(dest.with_file_name(format!(".{}.{}.part", name, transfer)))
+}
+
+pub async fn receive(
+    id: u64,
+    req: UploadRequest,
+    mut chunks: mpsc::Receiver<Chunk>,
+    writer: Writer,
+) -> Result<Response> {
+    let dest = resolve(&req.target, true)?;
+    let part = part_path(&dest, &req.transfer)?;
+
+    let mut offset = tokio::fs::metadata(&part)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+    if offset > req.size {
+        offset = 0;
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&part)
+        .await?;
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    if offset > 0 {
+        info!("Resuming upload {:?} at {} bytes", dest, offset);
+    }
+    let ready = TransferReady {
+        offset,
+        size: req.size,
+    };
+    reply(&writer, id, Response::TransferReady(ready)).await?;
+
+    let mut seq = 0;
+    while let Some(chunk) = tokio::time::timeout(IDLE_TIMEOUT, chunks.recv()).await? {
+        if chunk.seq != seq {
+            anyhow::bail!("Chunk out of order: expected {}, got {}", seq, chunk.seq);
+        }
+        seq += 1;
+
+        offset += chunk.data.len() as u64;
+        if offset > req.size {
+            anyhow::bail!("Got more than the announced {} bytes", req.size);
+        }
+
+        file.write_all(&chunk.data).await?;
+        reply(&writer, id, Response::TransferAck(TransferAck { offset })).await?;
+
+        if chunk.end {
+            if offset != req.size {
+                anyhow::bail!("Upload ended at {} of {} bytes", offset, req.size);
+            }
+
+            file.sync_all().await?;
+            tokio::fs::rename(&part, &dest).await?;
+            info!("Received {:?} ({} bytes)", dest, offset);
+            return Ok(Response::TransferDone(TransferDone { size: offset }));
+        }
+    }
+
+    // session gone, keep the part file for a resume
+    anyhow::bail!("Upload interrupted at {} of {} bytes", offset, req.size)
+}
+
+pub async fn send(
+    id: u64,
+    req: DownloadRequest,
+    mut acks: watch::Receiver<u64>,
+    writer: Writer,
+) -> Result<Response> {
+    let src = resolve(&req.target, false)?;
+    let mut file = tokio::fs::File::open(&src).await?;
+    let size = file.metadata().await?.len();
+
+    // client has a longer file than ours, it changed since: start over
+    let mut sent = if req.offset <= size { req.offset } else { 0 };
+    file.seek(SeekFrom::Start(sent)).await?;
+
+    let ready = TransferReady { offset: sent, size };
+    reply(&writer, id, Response::TransferReady(ready)).await?;
+
+    let window = TRANSFER_WINDOW * CHUNK_SIZE as u64;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+
+    for seq in 0.. {
+        while sent.saturating_sub(*acks.borrow()) >= window {
+            tokio::time::timeout(IDLE_TIMEOUT, acks.changed()).await??;
+        }
+
+        let n = file.read(&mut buf).await?;
+        sent += n as u64;
+
+        let chunk = Chunk {
+            stream: id,
+            seq,
+            end: n == 0 || sent >= size,
+            data: buf[..n].to_vec(),
+        };
+        send_chunk(&mut *writer.lock().await, &chunk).await?;
+
+        if chunk.end {
+            break;
+        }
+    }
+
+    info!("Sent {:?} ({} bytes)", src, sent);
+    Ok(Response::TransferDone(TransferDone { size: sent }))
+}
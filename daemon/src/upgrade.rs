@@ -0,0 +1,299 @@
+use anyhow::Result;
+use common::{Response, UpgradeRequest, UpgradeResponse};
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::config::Args;
+use crate::shutdown::Lifecycle;
+
+// listening sockets handed over by the previous binary, name -> fd
+static INHERITED: Mutex<Vec<(String, RawFd)>> = Mutex::new(Vec::new());
+// ours, passed on when re-exec'ing
+static LISTENERS: Mutex<Vec<(String, RawFd)>> = Mutex::new(Vec::new());
+// previous binary, set until we pass the startup check after an upgrade
+static BACKUP: OnceLock<PathBuf> = OnceLock::new();
+static CONFIRMED: AtomicBool = AtomicBool::new(false);
+static UPGRADING: AtomicBool = AtomicBool::new(false);
+
+pub fn init(args: &Args) {
+    if let Some(fds) = &args.inherit_fds {
+        let mut inherited = INHERITED.lock().unwrap();
+        for pair in fds.split(',') {
+            if let Some((name, fd)) = pair.split_once('=')
+                && let Ok(fd) = fd.parse()
+            {
+                // not for the apps we spawn
+                set_cloexec(fd, true);
+                inherited.push((name.to_string(), fd));
+            }
+        }
+    }
+    if let Some(backup) = &args.upgrade_backup {
+        let _ = BACKUP.set(backup.clone());
+    } else if args.command.is_none() {
+        recover();
+    }
+}
+
+// An upgrade that is still unconfirmed when we start without --upgrade-backup
+// died (or hung and got killed) before confirm(), and whatever restarted us
+// runs the new binary again. Put the previous one back instead.
+fn recover() {
+    let Ok(exe) = std::env::current_exe() else {
+        return;
+    };
+    let marker = sibling(&exe, ".unconfirmed");
+    if !marker.exists() {
+        return;
+    }
+    let _ = std::fs::remove_file(&marker);
+
+    let backup = sibling(&exe, ".old");
+    eprintln!("Last upgrade never came up, going back to {:?}", backup);
+    if let Err(e) = std::fs::rename(&backup, &exe) {
+        eprintln!("Rollback failed: {}", e);
+        return;
+    }
+    eprintln!("Rollback failed: {}", reexec(&exe, None));
+}
+
+// <exe><suffix> next to the binary
+fn sibling(exe: &Path, suffix: &str) -> PathBuf {
+    let name = exe.file_name().unwrap_or_default().to_string_lossy();
+    exe.with_file_name(format!("{}{}", name, suffix))
+}
+
+// Binds `addr`, or takes over the socket the previous binary was listening on,
+// so connections queue up instead of being refused while we restart.
+pub fn bind(name: &str, addr: &str) -> Result<TcpListener> {
+    let inherited = {
+        let mut inherited = INHERITED.lock().unwrap();
+        let pos = inherited.iter().position(|(n, _)| n == name);
+        pos.map(|i| inherited.remove(i).1)
+    };
+
+    let mut listener = None;
+    if let Some(fd) = inherited {
+        let l = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        // config may have moved the listener since
+        if l.local_addr().ok() == addr.parse::<SocketAddr>().ok() {
+            info!("Took over {} socket on {}", name, addr);
+            listener = Some(l);
+        }
+    }
+    let listener = match listener {
+        Some(l) => l,
+        None => std::net::TcpListener::bind(addr)?,
+    };
+
+    listener.set_nonblocking(true)?;
+    LISTENERS
+        .lock()
+        .unwrap()
+        .push((name.to_string(), listener.as_raw_fd()));
+    Ok(TcpListener::from_std(listener)?)
+}
+
+fn set_cloexec(fd: RawFd, on: bool) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        let flags = if on {
+            flags | libc::FD_CLOEXEC
+        } else {
+            flags & !libc::FD_CLOEXEC
+        };
+        libc::fcntl(fd, libc::F_SETFD, flags);
+    }
+}
+
+// upload slot for TransferTarget::DaemonBinary
+pub fn staged_path() -> PathBuf {
+    crate::config::get()
+        .data_dir
+        .join("upgrade")
+        .join("flared.new")
+}
+
+pub async fn handle(req: UpgradeRequest, lifecycle: Lifecycle) -> Result<Response> {
+    if UPGRADING.swap(true, Ordering::SeqCst) {
+        anyhow::bail!("Upgrade already in progress");
+    }
+    let result = prepare(&req);
+    let (exe, backup, version) = match result {
+        Ok(r) => r,
+        Err(e) => {
+            UPGRADING.store(false, Ordering::SeqCst);
+            return Err(e);
+        }
+    };
+
+    info!("Upgrading to {}, restarting", version);
+    tokio::spawn(async move {
+        // let the response go out first
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        lifecycle
+            .drain(crate::config::get().shutdown_timeout())
+            .await;
+
+        let err = reexec(&exe, Some(&backup));
+        error!("Re-exec failed: {}, keeping the old binary", err);
+        let _ = std::fs::remove_file(sibling(&exe, ".unconfirmed"));
+        if let Err(e) = std::fs::rename(&backup, &exe) {
+            error!("Can't restore {:?}: {}", exe, e);
+        }
+        lifecycle.reopen();
+        UPGRADING.store(false, Ordering::SeqCst);
+    });
+
+    Ok(Response::Upgrade(UpgradeResponse {
+        message: format!("Installed {}, restarting", version),
+        version,
+    }))
+}
+
+// verify and install the uploaded binary: (exe, backup of the old one, new version)
+fn prepare(req: &UpgradeRequest) -> Result<(PathBuf, PathBuf, String)> {
+    let staged = staged_path();
+    if !staged.exists() {
+        anyhow::bail!("No binary uploaded");
+    }
+
+    let sha256 = tokio::task::block_in_place(|| common::sha256_file(&staged))?;
+    if !sha256.eq_ignore_ascii_case(&req.sha256) {
+        anyhow::bail!("Checksum mismatch: got {}, expected {}", sha256, req.sha256);
+    }
+    verify_signature(&staged, req.signature.as_deref())?;
+
+    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755))?;
+    let version = self_check(&staged)?;
+
+    let exe = std::env::current_exe()?;
+    let backup = replace(&exe, &staged)?;
+    Ok((exe, backup, version))
+}
+
+fn verify_signature(path: &Path, signature: Option<&str>) -> Result<()> {
+    // a daemon token alone must not be enough to replace a root binary
+    let Some(key) = &crate::config::get().upgrade.public_key else {
+        anyhow::bail!("Upgrades are off, set upgrade.public_key in flared.toml to allow them");
+    };
+
+    let signature = signature.ok_or_else(|| anyhow::anyhow!("Binary must be signed"))?;
+    let key = hex::decode(key.trim()).map_err(|_| anyhow::anyhow!("Bad upgrade.public_key"))?;
+    let signature = hex::decode(signature).map_err(|_| anyhow::anyhow!("Bad signature"))?;
+
+    let data = std::fs::read(path)?;
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, key)
+        .verify(&data, &signature)
+        .map_err(|_| anyhow::anyhow!("Signature doesn't match"))
+}
+
+// the new binary must at least run on this machine
+fn self_check(path: &Path) -> Result<String> {
+    let output =
+        tokio::task::block_in_place(|| std::process::Command::new(path).arg("--version").output())
+            .map_err(|e| anyhow::anyhow!("New binary doesn't run: {}", e))?;
+
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || !version.starts_with("flared ") {
+        anyhow::bail!("New binary failed its self-check: {:?}", version);
+    }
+    Ok(version)
+}
+
+// Swaps the binary in place, the old one stays next to it as <exe>.old.
+// <exe>.unconfirmed is there until the new one passes its startup, see recover().
+fn replace(exe: &Path, staged: &Path) -> Result<PathBuf> {
+    let name = exe.file_name().unwrap_or_default().to_string_lossy();
+    let backup = sibling(exe, ".old");
+    let tmp = exe.with_file_name(format!(".{}.new", name));
+
+    std::fs::copy(exe, &backup)?;
+    // same filesystem as exe, so the rename is atomic
+    std::fs::copy(staged, &tmp)?;
+    std::fs::write(sibling(exe, ".unconfirmed"), "")?;
+    std::fs::rename(&tmp, exe)?;
+    let _ = std::fs::remove_file(staged);
+    Ok(backup)
+}
+
+// only returns on failure
+fn reexec(exe: &Path, backup: Option<&Path>) -> anyhow::Error {
+    // plus whatever we inherited but didn't get to bind, when rolling back
+    let mut listeners = LISTENERS.lock().unwrap().clone();
+    listeners.extend(INHERITED.lock().unwrap().iter().cloned());
+    for (_, fd) in &listeners {
+        set_cloexec(*fd, false);
+    }
+
+    // same arguments, minus what the previous binary added
+    let mut args = std::env::args_os().skip(1);
+    let mut cmd = std::process::Command::new(exe);
+    while let Some(arg) = args.next() {
+        if arg == "--inherit-fds" || arg == "--upgrade-backup" {
+            args.next();
+            continue;
+        }
+        cmd.arg(arg);
+    }
+
+    if !listeners.is_empty() {
+        let fds: Vec<_> = listeners
+            .iter()
+            .map(|(name, fd)| format!("{}={}", name, fd))
+            .collect();
+        cmd.arg("--inherit-fds").arg(fds.join(","));
+    }
+    if let Some(backup) = backup {
+        cmd.arg("--upgrade-backup").arg(backup);
+    }
+
+    let err = cmd.exec();
+    for (_, fd) in &listeners {
+        set_cloexec(*fd, true);
+    }
+    err.into()
+}
+
+// we made it through startup, the upgrade sticks
+pub fn confirm() {
+    // sockets of listeners we no longer have
+    for (name, fd) in INHERITED.lock().unwrap().drain(..) {
+        info!("Closing inherited {} socket", name);
+        unsafe { libc::close(fd) };
+    }
+
+    if let Some(backup) = BACKUP.get() {
+        CONFIRMED.store(true, Ordering::SeqCst);
+        if let Ok(exe) = std::env::current_exe() {
+            let _ = std::fs::remove_file(sibling(&exe, ".unconfirmed"));
+        }
+        info!("Upgrade confirmed, previous binary kept at {:?}", backup);
+    }
+}
+
+// Startup failed right after an upgrade: put the previous binary back and run it.
+// Returns only if there is nothing to roll back or the rollback failed.
+pub fn rollback() -> Option<anyhow::Error> {
+    let backup = BACKUP.get()?;
+    if CONFIRMED.load(Ordering::SeqCst) {
+        return None;
+    }
+
+    // backup is <exe>.old, see replace()
+    let name = backup.file_name().unwrap_or_default().to_string_lossy();
+    let exe = backup.with_file_name(name.trim_end_matches(".old"));
+    let _ = std::fs::remove_file(sibling(&exe, ".unconfirmed"));
+    if let Err(e) = std::fs::rename(backup, &exe) {
+        return Some(e.into());
+    }
+    Some(reexec(&exe, None))
+}